@@ -0,0 +1,91 @@
+use proc_macro::{Delimiter, Group, Ident, Literal, Punct, Spacing, Span, TokenStream, TokenTree};
+
+fn compile_error(message: &str) -> TokenStream {
+    format!("compile_error!({message:?});").parse().unwrap()
+}
+
+fn function_name(item: &[TokenTree]) -> Option<String> {
+    item.windows(2).find_map(|pair| match pair {
+        [TokenTree::Ident(keyword), TokenTree::Ident(name)] if keyword.to_string() == "fn" => {
+            Some(name.to_string())
+        }
+        _ => None,
+    })
+}
+
+fn path(segments: &[&str]) -> TokenStream {
+    let mut tokens = TokenStream::new();
+    for segment in segments {
+        tokens.extend([
+            TokenTree::Punct(Punct::new(':', Spacing::Joint)),
+            TokenTree::Punct(Punct::new(':', Spacing::Alone)),
+            TokenTree::Ident(Ident::new(segment, Span::call_site())),
+        ]);
+    }
+    tokens
+}
+
+// The path of the iperf-rs crate: `::iperf_rs` unless given as
+// `crate = "path"`, e.g. when the dependency is renamed or re-exported.
+fn crate_path(attr: TokenStream) -> Result<TokenStream, TokenStream> {
+    let attr: Vec<TokenTree> = attr.into_iter().collect();
+    match attr.as_slice() {
+        [] => Ok("::iperf_rs".parse().unwrap()),
+        [TokenTree::Ident(key), TokenTree::Punct(eq), TokenTree::Literal(value)]
+            if key.to_string() == "crate" && eq.as_char() == '=' =>
+        {
+            let value = value.to_string();
+            value
+                .strip_prefix('"')
+                .and_then(|value| value.strip_suffix('"'))
+                .and_then(|path| path.parse().ok())
+                .ok_or_else(|| compile_error("#[profile(crate = ...)] expects a path in a string"))
+        }
+        _ => Err(compile_error(
+            "#[profile] only takes a `crate = \"path\"` argument",
+        )),
+    }
+}
+
+/// Wraps the function body in a block on the thread's global profiler,
+/// labelled with the function's module path and name. The block is opened
+/// through `::iperf_rs`; `#[profile(crate = "path")]` names the crate by
+/// another path.
+#[proc_macro_attribute]
+pub fn profile(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let krate = match crate_path(attr) {
+        Ok(krate) => krate,
+        Err(error) => return error,
+    };
+
+    let mut item: Vec<TokenTree> = item.into_iter().collect();
+    let Some(name) = function_name(&item) else {
+        return compile_error("#[profile] can only be applied to functions");
+    };
+    let Some(TokenTree::Group(body)) = item.pop() else {
+        return compile_error("#[profile] requires a function body");
+    };
+    if body.delimiter() != Delimiter::Brace {
+        return compile_error("#[profile] requires a function body");
+    }
+
+    let label: TokenStream = format!(
+        "concat!(module_path!(), \"::\", {})",
+        Literal::string(&name)
+    )
+    .parse()
+    .unwrap();
+
+    let mut wrapped = TokenStream::new();
+    wrapped.extend("let _profile_guard =".parse::<TokenStream>().unwrap());
+    wrapped.extend(krate);
+    wrapped.extend(path(&["global", "begin_block"]));
+    wrapped.extend([
+        TokenTree::Group(Group::new(Delimiter::Parenthesis, label)),
+        TokenTree::Punct(Punct::new(';', Spacing::Alone)),
+    ]);
+    wrapped.extend(body.stream());
+
+    item.push(TokenTree::Group(Group::new(Delimiter::Brace, wrapped)));
+    item.into_iter().collect()
+}
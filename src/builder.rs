@@ -0,0 +1,237 @@
+use crate::{
+    CalibrationClock, CpuTimeSource, ProfileOutput, Profiler, ReportUnits, SortBy, TimeSource,
+    DEFAULT_PROFILE_CAPACITY,
+};
+#[cfg(feature = "fs")]
+use std::fs::File;
+use std::io::{stderr, Write};
+use std::time::Duration;
+
+pub struct ProfilerBuilder<T: TimeSource = CpuTimeSource> {
+    time_source: T,
+    output: Option<ProfileOutput>,
+    recalibrate: bool,
+    report_units: ReportUnits,
+    histograms: bool,
+    capacity: usize,
+    sort_by: SortBy,
+    top_n: Option<usize>,
+    no_color: bool,
+    print_on_drop: bool,
+    precision: Option<usize>,
+    #[cfg(feature = "fs")]
+    append: bool,
+}
+
+impl ProfilerBuilder {
+    pub fn new() -> Self {
+        Self::with_time_source(CpuTimeSource::default())
+    }
+
+    /// Replaces the time source with a hardware counter calibrated over `calibration`.
+    pub fn calibration(mut self, calibration: Duration) -> Self {
+        self.time_source =
+            CpuTimeSource::with_calibration(calibration).with_clock(self.time_source.clock());
+        self.recalibrate = true;
+        self
+    }
+
+    pub fn calibration_clock(mut self, clock: CalibrationClock) -> Self {
+        self.time_source = self.time_source.with_clock(clock);
+        self.recalibrate = true;
+        self
+    }
+}
+
+impl Default for ProfilerBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: TimeSource> ProfilerBuilder<T> {
+    pub fn with_time_source(time_source: T) -> Self {
+        Self {
+            time_source,
+            output: None,
+            recalibrate: false,
+            report_units: ReportUnits::default(),
+            histograms: false,
+            capacity: DEFAULT_PROFILE_CAPACITY,
+            sort_by: SortBy::default(),
+            top_n: None,
+            no_color: false,
+            print_on_drop: false,
+            precision: None,
+            #[cfg(feature = "fs")]
+            append: false,
+        }
+    }
+
+    /// Prints reports to `file` instead of the `PROFILE_OUT` file or stdout.
+    #[cfg(feature = "fs")]
+    pub fn output(mut self, file: File) -> Self {
+        self.output = Some(ProfileOutput::File(file));
+        self
+    }
+
+    /// Prints reports to `sink` instead of the `PROFILE_OUT` file or stdout.
+    pub fn sink<W: Write + Send + 'static>(mut self, sink: W) -> Self {
+        self.output = Some(ProfileOutput::Sink(Box::new(sink)));
+        self
+    }
+
+    /// Appends to the `PROFILE_OUT` file instead of truncating it, as if
+    /// `PROFILE_OUT_APPEND` were set.
+    #[cfg(feature = "fs")]
+    pub fn append(mut self, append: bool) -> Self {
+        self.append = append;
+        self
+    }
+
+    pub fn stderr(mut self) -> Self {
+        self.output = Some(ProfileOutput::Stderr(stderr()));
+        self
+    }
+
+    pub fn units(mut self, units: ReportUnits) -> Self {
+        self.report_units = units;
+        self
+    }
+
+    pub fn histograms(mut self, enabled: bool) -> Self {
+        self.histograms = enabled;
+        self
+    }
+
+    pub fn capacity(mut self, capacity: usize) -> Self {
+        self.capacity = capacity;
+        self
+    }
+
+    pub fn sort_by(mut self, sort_by: SortBy) -> Self {
+        self.sort_by = sort_by;
+        self
+    }
+
+    pub fn top_n(mut self, top_n: Option<usize>) -> Self {
+        self.top_n = top_n;
+        self
+    }
+
+    pub fn no_color(mut self, no_color: bool) -> Self {
+        self.no_color = no_color;
+        self
+    }
+
+    pub fn print_on_drop(mut self, print_on_drop: bool) -> Self {
+        self.print_on_drop = print_on_drop;
+        self
+    }
+
+    /// Decimals for times and throughput in the text report, see `Profiler::set_precision`.
+    pub fn precision(mut self, digits: usize) -> Self {
+        self.precision = Some(digits);
+        self
+    }
+
+    pub fn build(self) -> Profiler<T> {
+        let mut profiler = match self.output {
+            Some(output) => Profiler::with_output(self.time_source, output),
+            #[cfg(feature = "fs")]
+            None => Profiler::with_env_output(
+                self.time_source,
+                self.append || crate::output::append_requested(),
+            ),
+            #[cfg(not(feature = "fs"))]
+            None => Profiler::with_time_source(self.time_source),
+        };
+        if self.recalibrate {
+            profiler.recalibrate();
+        }
+
+        profiler.report_units = self.report_units;
+        profiler.histograms_enabled = self.histograms;
+        profiler.capacity = self.capacity;
+        profiler.sort_by = self.sort_by;
+        profiler.top_n = self.top_n;
+        profiler.color = !self.no_color;
+        profiler.print_on_drop = self.print_on_drop;
+        profiler.precision = self.precision;
+        profiler
+    }
+}
+
+#[cfg(all(test, feature = "profiling"))]
+mod tests {
+    use super::*;
+    use crate::tests::Captured;
+    use crate::timer::MockTimeSource;
+
+    #[cfg(feature = "fs")]
+    #[test]
+    fn builder_options_reach_the_report() {
+        let path = std::env::temp_dir().join(format!("iperf-rs-{}-builder", std::process::id()));
+        let clock = MockTimeSource::new(1000);
+        let mut profiler = ProfilerBuilder::with_time_source(clock.clone())
+            .output(File::create(&path).unwrap())
+            .units(ReportUnits::Bits)
+            .histograms(true)
+            .top_n(Some(1))
+            .build();
+        profiler.start();
+        for (label, ticks) in [("send", 30), ("recv", 20), ("parse", 10)] {
+            let block = profiler.begin_block(label);
+            clock.advance(ticks);
+            drop(block);
+        }
+        clock.advance(40);
+        profiler.end_and_print_results();
+
+        let output = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let anchors: Vec<&str> = output.lines().skip(5).collect();
+        assert_eq!(anchors.len(), 2, "{output}");
+        assert!(anchors[0].starts_with("send[1]"), "{output}");
+        assert!(anchors[1].starts_with("(other)"), "{output}");
+        assert!(anchors[0].contains('±'), "{output}");
+    }
+
+    #[test]
+    fn print_on_drop_reports_at_scope_exit() {
+        let captured = Captured::default();
+        let clock = MockTimeSource::new(1000);
+        {
+            let mut profiler = ProfilerBuilder::with_time_source(clock.clone())
+                .sink(captured.clone())
+                .print_on_drop(true)
+                .build();
+            profiler.start();
+            let block = profiler.begin_block("send");
+            clock.advance(10);
+            drop(block);
+            assert_eq!(captured.text(), "");
+        }
+
+        let output = captured.text();
+        assert!(output.starts_with("Performance report:"), "{output}");
+        assert!(output.contains("send[1]"), "{output}");
+    }
+
+    #[test]
+    fn reports_reach_a_sink_without_touching_files() {
+        let captured = Captured::default();
+        let clock = MockTimeSource::new(1000);
+        let mut profiler = ProfilerBuilder::with_time_source(clock.clone())
+            .sink(captured.clone())
+            .build();
+        profiler.start();
+        profiler.profile("send", || clock.advance(25));
+        clock.advance(75);
+        profiler.end_and_print_results();
+
+        let output = captured.text();
+        assert!(output.contains("Total time = 100.0ms"), "{output}");
+        assert!(output.contains("send[1]: 25.00ms (25.00%"), "{output}");
+    }
+}
@@ -0,0 +1,119 @@
+//! Loss, reordering and jitter of a UDP stream, like iperf's `-u` server report.
+//!
+//! The sender numbers its datagrams and stamps them with its send time; the
+//! receive loop passes both to [`DatagramStats::record`] with the arrival time.
+
+use crate::report::ratio;
+use core::time::Duration;
+
+/// Counters a UDP receive loop updates with every datagram it gets.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DatagramStats {
+    received: u64,
+    lost: u64,
+    out_of_order: u64,
+    next_sequence: Option<u64>,
+    // Seconds; the two clocks needn't agree, only the differences are used.
+    last_transit: Option<f64>,
+    jitter: f64,
+}
+
+/// What [`DatagramStats`] counted so far.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DatagramReport {
+    /// Datagrams the sender sent up to the highest sequence number seen.
+    pub total: u64,
+    pub lost: u64,
+    pub out_of_order: u64,
+    pub loss_pct: f64,
+    pub jitter_ms: f64,
+}
+
+impl DatagramStats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Counts the datagram numbered `sequence`, sent at `sent` by the sender's
+    /// clock and received at `arrived` by ours.
+    ///
+    /// A gap in the sequence counts the missing datagrams as lost; one that
+    /// arrives late after all counts as out of order instead. Duplicates count
+    /// as out of order too.
+    pub fn record(&mut self, sequence: u64, sent: Duration, arrived: Duration) {
+        self.received += 1;
+        let next = self.next_sequence.unwrap_or(sequence);
+        if sequence >= next {
+            self.lost += sequence - next;
+            self.next_sequence = Some(sequence + 1);
+        } else {
+            self.out_of_order += 1;
+            self.lost = self.lost.saturating_sub(1);
+        }
+
+        // RFC 3550's interarrival jitter, smoothed with a gain of 1/16.
+        let transit = arrived.as_secs_f64() - sent.as_secs_f64();
+        if let Some(last_transit) = self.last_transit {
+            self.jitter += ((transit - last_transit).abs() - self.jitter) / 16.0;
+        }
+        self.last_transit = Some(transit);
+    }
+
+    pub fn report(&self) -> DatagramReport {
+        let total = self.received + self.lost;
+        DatagramReport {
+            total,
+            lost: self.lost,
+            out_of_order: self.out_of_order,
+            loss_pct: 100.0 * ratio(self.lost as f64, total as f64),
+            jitter_ms: self.jitter * 1000.0,
+        }
+    }
+
+    // Streams of different threads are numbered separately, so only the counts
+    // add up; the jitter is the worse of the two.
+    pub(crate) fn merge(&mut self, other: &DatagramStats) {
+        self.received += other.received;
+        self.lost += other.lost;
+        self.out_of_order += other.out_of_order;
+        self.jitter = self.jitter.max(other.jitter);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(millis: u64) -> Duration {
+        Duration::from_millis(millis)
+    }
+
+    #[test]
+    fn gaps_are_lost_and_late_arrivals_out_of_order() {
+        let mut stats = DatagramStats::new();
+        // 2 arrives after 3, 4 never does.
+        for sequence in [0, 1, 3, 2, 5] {
+            stats.record(sequence, at(sequence * 10), at(sequence * 10 + 5));
+        }
+
+        let report = stats.report();
+        assert_eq!(report.total, 6);
+        assert_eq!(report.lost, 1);
+        assert_eq!(report.out_of_order, 1);
+        assert!((report.loss_pct - 100.0 / 6.0).abs() < 1e-9);
+        // Every datagram took the same 5ms.
+        assert!(report.jitter_ms < 1e-6, "{}", report.jitter_ms);
+    }
+
+    #[test]
+    fn jitter_follows_changes_in_transit_time() {
+        let mut stats = DatagramStats::new();
+        stats.record(0, at(0), at(5));
+        stats.record(1, at(10), at(31));
+
+        // A 16ms change with the 1/16 gain.
+        assert!((stats.report().jitter_ms - 1.0).abs() < 1e-9);
+        assert_eq!(stats.report().lost, 0);
+    }
+}
@@ -0,0 +1,644 @@
+use crate::report::{ratio, AnchorReport, ProfileReport, Rfc3339, ScaledMs};
+use std::collections::{HashMap, HashSet};
+use std::io::{self, Write};
+use std::time::SystemTime;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FoldedSamples {
+    #[default]
+    ExclusiveTicks,
+    Hits,
+}
+
+const TRACE_PID: u32 = 1;
+const TRACE_TID: u32 = 1;
+
+fn write_json_string<W: Write>(w: &mut W, value: &str) -> io::Result<()> {
+    write!(w, "\"")?;
+    for c in value.chars() {
+        match c {
+            '"' => write!(w, "\\\"")?,
+            '\\' => write!(w, "\\\\")?,
+            '\n' => write!(w, "\\n")?,
+            '\r' => write!(w, "\\r")?,
+            '\t' => write!(w, "\\t")?,
+            c if (c as u32) < 0x20 => write!(w, "\\u{:04x}", c as u32)?,
+            c => write!(w, "{c}")?,
+        }
+    }
+    write!(w, "\"")
+}
+
+fn folded_frame(label: &str) -> String {
+    label
+        .chars()
+        .map(|c| match c {
+            ';' => '_',
+            '\n' | '\r' => ' ',
+            c => c,
+        })
+        .collect()
+}
+
+struct CallTree<'a> {
+    anchors: &'a [AnchorReport],
+    roots: Vec<usize>,
+    children: HashMap<&'a str, Vec<usize>>,
+}
+
+impl<'a> CallTree<'a> {
+    fn new(anchors: &'a [AnchorReport]) -> Self {
+        let labels: HashSet<&str> = anchors.iter().map(|anchor| anchor.label.as_str()).collect();
+        let mut roots = Vec::new();
+        let mut children: HashMap<&str, Vec<usize>> = HashMap::new();
+
+        for (index, anchor) in anchors.iter().enumerate() {
+            match anchor.parent.as_deref() {
+                Some(parent) if parent != anchor.label && labels.contains(parent) => {
+                    children.entry(parent).or_default().push(index)
+                }
+                _ => roots.push(index),
+            }
+        }
+
+        Self {
+            anchors,
+            roots,
+            children,
+        }
+    }
+
+    fn children(&self, index: usize) -> &[usize] {
+        self.children
+            .get(self.anchors[index].label.as_str())
+            .map_or(&[], Vec::as_slice)
+    }
+}
+
+impl ProfileReport {
+    pub fn write_chrome_trace<W: Write>(&self, mut w: W) -> io::Result<()> {
+        let tree = CallTree::new(&self.anchors);
+        let mut visited = HashSet::new();
+        let mut first = true;
+        let mut ts = 0.0;
+
+        write!(w, "{{\"traceEvents\":[")?;
+        for &root in &tree.roots {
+            ts += self.write_trace_event(&mut w, &tree, root, ts, &mut visited, &mut first)?;
+        }
+        writeln!(w, "],\"displayTimeUnit\":\"ns\"}}")
+    }
+
+    fn write_trace_event<W: Write>(
+        &self,
+        w: &mut W,
+        tree: &CallTree,
+        index: usize,
+        ts: f64,
+        visited: &mut HashSet<usize>,
+        first: &mut bool,
+    ) -> io::Result<f64> {
+        if !visited.insert(index) {
+            return Ok(0.0);
+        }
+
+        let anchor = &self.anchors[index];
+        let dur = anchor.inclusive_ms * 1000.0;
+
+        if !*first {
+            write!(w, ",")?;
+        }
+        *first = false;
+
+        write!(w, "{{\"name\":")?;
+        write_json_string(w, &anchor.label)?;
+        write!(
+            w,
+            ",\"ph\":\"X\",\"ts\":{ts:.3},\"dur\":{dur:.3},\"pid\":{TRACE_PID},\"tid\":{TRACE_TID},\"args\":{{\"hits\":{}}}}}",
+            anchor.hits
+        )?;
+
+        let mut child_ts = ts;
+        for &child in tree.children(index) {
+            child_ts += self.write_trace_event(w, tree, child, child_ts, visited, first)?;
+        }
+
+        Ok(dur)
+    }
+}
+
+impl ProfileReport {
+    pub fn write_folded<W: Write>(&self, mut w: W, samples: FoldedSamples) -> io::Result<()> {
+        let tree = CallTree::new(&self.anchors);
+        let mut visited = HashSet::new();
+        let mut stack = Vec::new();
+
+        for &root in &tree.roots {
+            self.write_folded_stack(&mut w, &tree, root, samples, &mut stack, &mut visited)?;
+        }
+        Ok(())
+    }
+
+    fn write_folded_stack<W: Write>(
+        &self,
+        w: &mut W,
+        tree: &CallTree,
+        index: usize,
+        samples: FoldedSamples,
+        stack: &mut Vec<String>,
+        visited: &mut HashSet<usize>,
+    ) -> io::Result<()> {
+        if !visited.insert(index) {
+            return Ok(());
+        }
+
+        let anchor = &self.anchors[index];
+        stack.push(folded_frame(&anchor.label));
+
+        let count = match samples {
+            FoldedSamples::ExclusiveTicks => anchor.exclusive_ticks,
+            FoldedSamples::Hits => anchor.hits,
+        };
+        if count != 0 {
+            writeln!(w, "{} {count}", stack.join(";"))?;
+        }
+
+        for &child in tree.children(index) {
+            self.write_folded_stack(w, tree, child, samples, stack, visited)?;
+        }
+
+        stack.pop();
+        Ok(())
+    }
+}
+
+impl ProfileReport {
+    pub fn write_tree<W: Write>(&self, mut w: W) -> io::Result<()> {
+        let tree = CallTree::new(&self.anchors);
+        let mut visited = HashSet::new();
+
+        writeln!(w, "Call tree:")?;
+        for &root in &tree.roots {
+            self.write_tree_node(&mut w, &tree, root, None, 0, &mut visited)?;
+        }
+        Ok(())
+    }
+
+    fn write_tree_node<W: Write>(
+        &self,
+        w: &mut W,
+        tree: &CallTree,
+        index: usize,
+        parent: Option<&AnchorReport>,
+        depth: usize,
+        visited: &mut HashSet<usize>,
+    ) -> io::Result<()> {
+        if !visited.insert(index) {
+            return Ok(());
+        }
+
+        let anchor = &self.anchors[index];
+        // Under a parent only the hits it made count, as in the text report, so
+        // an anchor with several callers can't take more than the parent's time.
+        let (share, of) = match parent {
+            Some(_) => (anchor.percent_of_parent.unwrap_or(0.0) / 100.0, "parent"),
+            None => (
+                ratio(anchor.inclusive_ticks as f64, self.total_ticks as f64),
+                "total",
+            ),
+        };
+
+        write!(
+            w,
+            "{:indent$}{}[{}]: {} ({:.2}% of {of})",
+            "",
+            anchor.label,
+            anchor.hits,
+            ScaledMs(anchor.inclusive_ms),
+            100.0 * share,
+            indent = depth * 4
+        )?;
+
+        let other_callers: Vec<&str> = anchor
+            .callers
+            .iter()
+            .map(String::as_str)
+            .filter(|&caller| Some(caller) != anchor.parent.as_deref())
+            .collect();
+        if !other_callers.is_empty() {
+            write!(w, " (also called from {})", other_callers.join(", "))?;
+        }
+        writeln!(w)?;
+
+        for &child in tree.children(index) {
+            self.write_tree_node(w, tree, child, Some(anchor), depth + 1, visited)?;
+        }
+        Ok(())
+    }
+}
+
+fn openmetrics_label_value(label: &str) -> String {
+    let mut value = String::with_capacity(label.len());
+    for c in label.chars() {
+        match c {
+            '"' => value.push_str("\\\""),
+            '\\' => value.push_str("\\\\"),
+            '\n' => value.push_str("\\n"),
+            c => value.push(c),
+        }
+    }
+    value
+}
+
+impl ProfileReport {
+    // Counters per anchor; seconds are inclusive, i.e. the time spent inside the block.
+    pub fn write_openmetrics<W: Write>(&self, mut w: W) -> io::Result<()> {
+        let anchors: Vec<(String, &AnchorReport)> = self
+            .anchors
+            .iter()
+            .chain(&self.other)
+            .map(|anchor| (openmetrics_label_value(&anchor.label), anchor))
+            .collect();
+
+        writeln!(w, "# TYPE profile_block_seconds counter")?;
+        writeln!(
+            w,
+            "# HELP profile_block_seconds Time spent inside the block."
+        )?;
+        for (label, anchor) in &anchors {
+            writeln!(
+                w,
+                "profile_block_seconds_total{{label=\"{label}\"}} {}",
+                anchor.inclusive_ms / 1000.0
+            )?;
+        }
+
+        writeln!(w, "# TYPE profile_block_hits counter")?;
+        writeln!(w, "# HELP profile_block_hits Times the block was entered.")?;
+        for (label, anchor) in &anchors {
+            writeln!(
+                w,
+                "profile_block_hits_total{{label=\"{label}\"}} {}",
+                anchor.hits
+            )?;
+        }
+
+        writeln!(w, "# TYPE profile_throughput_bytes counter")?;
+        writeln!(
+            w,
+            "# HELP profile_throughput_bytes Bytes processed by the block."
+        )?;
+        for (label, anchor) in &anchors {
+            writeln!(
+                w,
+                "profile_throughput_bytes_total{{label=\"{label}\"}} {}",
+                anchor.bytes
+            )?;
+        }
+
+        writeln!(w, "# EOF")
+    }
+}
+
+// Quotes a field per RFC 4180 when it holds a delimiter, quote or line break.
+fn csv_field(value: &str) -> std::borrow::Cow<'_, str> {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\"")).into()
+    } else {
+        value.into()
+    }
+}
+
+impl ProfileReport {
+    pub fn write_csv<W: Write>(&self, mut w: W) -> io::Result<()> {
+        writeln!(
+            w,
+            "label,hits,exclusive_ms,inclusive_ms,pct,pct_w_children,bytes,mb_per_s,started_at"
+        )?;
+        let started_at = self
+            .started_at
+            .map(|started_at| Rfc3339(started_at).to_string())
+            .unwrap_or_default();
+        for anchor in self.anchors.iter().chain(&self.other) {
+            writeln!(
+                w,
+                "{},{},{},{},{},{},{},{},{started_at}",
+                csv_field(&anchor.label),
+                anchor.hits,
+                anchor.exclusive_ms,
+                anchor.inclusive_ms,
+                anchor.percent,
+                anchor.percent_with_children,
+                anchor.bytes,
+                anchor.bytes_per_second / (1024.0 * 1024.0)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+// Escapes the characters line protocol treats as delimiters in a measurement
+// or tag value. Line breaks end a point, so they are written out as `\n`.
+fn influx_escape(value: &str, delimiters: &[char]) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\n' => escaped.push_str("\\n"),
+            c if delimiters.contains(&c) => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+impl ProfileReport {
+    /// One InfluxDB line-protocol point per anchor, e.g. for Telegraf's exec
+    /// input. Points are stamped in nanoseconds with the wall-clock time the run
+    /// ended, or the report was taken while it runs, and left for the server to
+    /// stamp when the run was never started.
+    pub fn write_influx_line<W: Write>(&self, mut w: W, measurement: &str) -> io::Result<()> {
+        let measurement = influx_escape(measurement, &[',', ' ']);
+        let timestamp = self
+            .ended_at
+            .and_then(|ended_at| ended_at.duration_since(SystemTime::UNIX_EPOCH).ok())
+            .map(|since_epoch| format!(" {}", since_epoch.as_nanos()))
+            .unwrap_or_default();
+        for anchor in self.anchors.iter().chain(&self.other) {
+            // Line protocol rejects empty tag values, so an empty label gets no tag.
+            let label = match anchor.label.as_str() {
+                "" => String::new(),
+                label => format!(",label={}", influx_escape(label, &[',', '=', ' '])),
+            };
+            writeln!(
+                w,
+                "{measurement}{label} exclusive_ms={:?},inclusive_ms={:?},hits={}i,bytes={}i,mbps={:?}{timestamp}",
+                anchor.exclusive_ms,
+                anchor.inclusive_ms,
+                anchor.hits,
+                anchor.bytes,
+                anchor.bytes_per_second * 8.0 / 1_000_000.0
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{record, started};
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn chrome_trace_nests_children_inside_parents() {
+        let (clock, profiler) = started(1000);
+        let outer = record(&profiler, "outer", 200, 1000, 1);
+        let first = record(&profiler, "first", 300, 300, 1);
+        let second = record(&profiler, "second", 500, 500, 1);
+        profiler.anchors.borrow_mut()[first].parent_index = outer;
+        profiler.anchors.borrow_mut()[second].parent_index = outer;
+        clock.advance(1000);
+
+        let mut trace = Vec::new();
+        profiler.write_chrome_trace(&mut trace).unwrap();
+        let value: serde_json::Value = serde_json::from_slice(&trace).unwrap();
+        let events = value["traceEvents"].as_array().unwrap();
+        assert_eq!(events.len(), 3);
+
+        let duration = |name: &str| {
+            events
+                .iter()
+                .find(|event| event["name"] == name)
+                .and_then(|event| event["dur"].as_f64())
+                .unwrap()
+        };
+        assert!(duration("outer") >= duration("first") + duration("second"));
+    }
+
+    #[test]
+    fn folded_stacks_follow_the_parent_chain() {
+        let (clock, profiler) = started(1000);
+        let outer = record(&profiler, "read file", 200, 500, 1);
+        let inner = record(&profiler, "parse;line", 300, 300, 3);
+        profiler.anchors.borrow_mut()[inner].parent_index = outer;
+        clock.advance(500);
+
+        let mut folded = Vec::new();
+        profiler
+            .write_folded(&mut folded, FoldedSamples::ExclusiveTicks)
+            .unwrap();
+        let folded = String::from_utf8(folded).unwrap();
+        for line in folded.lines() {
+            let (_, count) = line.rsplit_once(' ').unwrap();
+            assert!(count.parse::<u64>().is_ok(), "{line}");
+        }
+        assert_eq!(folded, "read file 200\nread file;parse_line 300\n");
+
+        let mut folded = Vec::new();
+        profiler
+            .write_folded(&mut folded, FoldedSamples::Hits)
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(folded).unwrap(),
+            "read file 1\nread file;parse_line 3\n"
+        );
+    }
+
+    #[cfg(feature = "profiling")]
+    #[test]
+    fn tree_indents_children_under_parents() {
+        let (clock, profiler) = started(1000);
+        {
+            let _send = profiler.begin_block("send");
+            clock.advance(10);
+            {
+                let _serialize = profiler.begin_block("serialize");
+                clock.advance(20);
+                let _write = profiler.begin_block("socket write");
+                clock.advance(30);
+            }
+            let _write = profiler.begin_block("socket write");
+            clock.advance(40);
+        }
+
+        let mut tree = Vec::new();
+        profiler.report().write_tree(&mut tree).unwrap();
+        let tree = String::from_utf8(tree).unwrap();
+        let depths: Vec<(usize, &str)> = tree
+            .lines()
+            .skip(1)
+            .map(|line| {
+                let label = line.trim_start();
+                (
+                    (line.len() - label.len()) / 4,
+                    label.split('[').next().unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(depths, [(0, "send"), (1, "socket write"), (1, "serialize")]);
+        assert!(tree.contains("serialize[1]: 50.00ms (50.00% of parent)"));
+        assert!(tree.contains("(also called from serialize)"));
+    }
+
+    #[test]
+    fn openmetrics_lines_are_well_formed() {
+        let (clock, profiler) = started(1000);
+        let send = record(&profiler, "udp::send", 250, 500, 4);
+        profiler.anchors.borrow_mut()[send].bytes_processed = 4096;
+        record(&profiler, "say \"hi\"\\", 10, 10, 1);
+        clock.advance(1000);
+
+        let mut metrics = Vec::new();
+        profiler.write_openmetrics(&mut metrics).unwrap();
+        let metrics = String::from_utf8(metrics).unwrap();
+        assert_eq!(metrics.lines().last(), Some("# EOF"));
+
+        let mut samples = Vec::new();
+        for line in metrics.lines().filter(|line| !line.starts_with('#')) {
+            let (name, rest) = line.split_once("{label=\"").unwrap();
+            let (label, value) = rest.rsplit_once("\"} ").unwrap();
+            assert!(name.chars().all(|c| c.is_ascii_lowercase() || c == '_'));
+            assert!(value.parse::<f64>().is_ok(), "{line}");
+            samples.push((name, label, value));
+        }
+        assert!(samples.contains(&("profile_block_seconds_total", "udp::send", "0.5")));
+        assert!(samples.contains(&("profile_block_hits_total", "udp::send", "4")));
+        assert!(samples.contains(&("profile_throughput_bytes_total", "udp::send", "4096")));
+        assert!(samples.contains(&("profile_block_hits_total", r#"say \"hi\"\\"#, "1")));
+    }
+
+    #[cfg(feature = "profiling")]
+    #[test]
+    fn csv_quotes_awkward_labels() {
+        // Splits one RFC 4180 record, undoing the quoting.
+        fn parse_record(line: &str) -> Vec<String> {
+            let (mut fields, mut field, mut quoted) = (Vec::new(), String::new(), false);
+            let mut chars = line.chars().peekable();
+            while let Some(c) = chars.next() {
+                match c {
+                    '"' if quoted && chars.peek() == Some(&'"') => {
+                        field.push('"');
+                        chars.next();
+                    }
+                    '"' => quoted = !quoted,
+                    ',' if !quoted => fields.push(std::mem::take(&mut field)),
+                    c => field.push(c),
+                }
+            }
+            fields.push(field);
+            fields
+        }
+
+        let (clock, mut profiler) = started(1000);
+        record(&profiler, "send, \"fast\"", 60, 60, 3);
+        record(&profiler, "recv", 40, 40, 1);
+        clock.advance(100);
+        profiler.stop();
+
+        let mut csv = Vec::new();
+        profiler.write_csv(&mut csv).unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+        let records: Vec<Vec<String>> = csv.lines().map(parse_record).collect();
+        assert!(records.iter().all(|record| record.len() == 9), "{csv}");
+        assert_eq!(records[0][0], "label");
+        assert_eq!(records[1][..2], ["send, \"fast\"", "3"]);
+        assert_eq!(records[2][..2], ["recv", "1"]);
+    }
+
+    #[cfg(feature = "profiling")]
+    #[test]
+    fn influx_lines_are_escaped_and_stamped() {
+        let (clock, profiler) = started(1000);
+        profiler.profile_with_bandwidth("tcp send,fast", 125_000, || clock.advance(1000));
+
+        let mut out = Vec::new();
+        profiler.write_influx_line(&mut out, "iperf run").unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let started = profiler.started_at().unwrap();
+        let started = started.duration_since(SystemTime::UNIX_EPOCH).unwrap();
+
+        let line = out.strip_suffix('\n').unwrap();
+        assert_eq!(line.lines().count(), 1, "{out}");
+        let (rest, timestamp) = line.rsplit_once(' ').unwrap();
+        assert_eq!(
+            rest,
+            "iperf\\ run,label=tcp\\ send\\,fast \
+             exclusive_ms=1000.0,inclusive_ms=1000.0,hits=1i,bytes=125000i,mbps=1.0"
+        );
+        assert!(timestamp.parse::<u128>().unwrap() >= started.as_nanos());
+    }
+
+    #[cfg(feature = "profiling")]
+    #[test]
+    fn influx_points_are_stamped_with_the_wall_clock_end() {
+        let (clock, mut profiler) = started(1000);
+        // An hour of ticks that took no wall-clock time at all.
+        profiler.profile("send", || clock.advance(3_600_000));
+        profiler.stop();
+        let stopped = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH);
+        let timestamp = || {
+            let mut out = Vec::new();
+            profiler.write_influx_line(&mut out, "iperf").unwrap();
+            let out = String::from_utf8(out).unwrap();
+            out.trim_end()
+                .rsplit_once(' ')
+                .unwrap()
+                .1
+                .parse::<u128>()
+                .unwrap()
+        };
+
+        let stamped = timestamp();
+        assert!(stamped <= stopped.unwrap().as_nanos());
+        std::thread::sleep(std::time::Duration::from_millis(2));
+        assert_eq!(timestamp(), stamped);
+    }
+
+    #[test]
+    fn influx_leaves_an_empty_label_untagged() {
+        let (clock, profiler) = started(1000);
+        record(&profiler, "", 500, 500, 1);
+        record(&profiler, "send", 250, 250, 1);
+        clock.advance(1000);
+
+        let mut out = Vec::new();
+        profiler.write_influx_line(&mut out, "iperf").unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(lines.len(), 2, "{out}");
+        assert!(lines[0].starts_with("iperf exclusive_ms=500.0,"), "{out}");
+        assert!(
+            lines[1].starts_with("iperf,label=send exclusive_ms=250.0,"),
+            "{out}"
+        );
+    }
+
+    #[cfg(feature = "profiling")]
+    #[test]
+    fn tree_shares_count_only_the_hits_under_the_parent() {
+        let (clock, profiler) = started(1000);
+        profiler.profile("recv", || {
+            clock.advance(100);
+            profiler.profile("crc", || clock.advance(1000));
+        });
+        profiler.profile("send", || {
+            clock.advance(90);
+            profiler.profile("crc", || clock.advance(10));
+        });
+
+        let report = profiler.report();
+        let mut tree = Vec::new();
+        report.write_tree(&mut tree).unwrap();
+        let tree = String::from_utf8(tree).unwrap();
+        let crc = tree.lines().find(|line| line.contains("crc[")).unwrap();
+        assert!(
+            crc.ends_with("(10.00% of parent) (also called from recv)"),
+            "{tree}"
+        );
+        let crc = report.anchors.iter().find(|anchor| anchor.label == "crc");
+        assert_eq!(crc.unwrap().percent_of_parent, Some(10.0));
+    }
+}
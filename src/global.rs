@@ -0,0 +1,126 @@
+//! A per-thread global profiler for code that can't thread a `&Profiler` around.
+//!
+//! Every thread lazily gets its own [`Profiler`], started the first time it is
+//! touched and kept until the thread exits. Blocks opened on one thread are
+//! only ever recorded into that thread's profiler, so reports taken here
+//! describe the calling thread alone.
+
+use crate::{OpenBlock, ProfileOutput, ProfileReport, Profiler, ReportUnits};
+use std::io::stdout;
+use std::marker::PhantomData;
+
+pub use iperf_rs_macros::profile;
+
+thread_local! {
+    static PROFILER: Profiler = {
+        let mut profiler = Profiler::with_output(Default::default(), ProfileOutput::Stdout(stdout()));
+        profiler.start();
+        profiler
+    };
+}
+
+/// A block on the calling thread's global profiler, recorded when dropped.
+/// Like [`ProfileBlock`](crate::ProfileBlock) it must stay on that thread:
+///
+/// ```compile_fail
+/// let block = iperf_rs::global::begin_block("moved");
+/// std::thread::spawn(move || drop(block));
+/// ```
+pub struct Block {
+    #[cfg_attr(not(feature = "profiling"), allow(dead_code))]
+    open: OpenBlock,
+    _not_send: PhantomData<*const ()>,
+}
+
+#[cfg(feature = "profiling")]
+impl Drop for Block {
+    fn drop(&mut self) {
+        // Blocks still open while the thread exits may outlive its profiler;
+        // they go unrecorded.
+        let _ = PROFILER.try_with(|profiler| self.open.end(profiler));
+    }
+}
+
+/// Calls `f` with the calling thread's profiler.
+pub fn with<R>(f: impl FnOnce(&Profiler) -> R) -> R {
+    PROFILER.with(f)
+}
+
+#[inline]
+pub fn begin_block(label: &str) -> Block {
+    begin_block_with_bandwidth(label, 0)
+}
+
+#[inline]
+pub fn begin_block_with_bandwidth(label: &str, bytes: u64) -> Block {
+    Block {
+        open: with(|profiler| profiler.begin_block_with_bandwidth(label, bytes).detach()),
+        _not_send: PhantomData,
+    }
+}
+
+pub fn report() -> ProfileReport {
+    with(|profiler| profiler.report())
+}
+
+pub fn print_results() {
+    let _ = report().write_text(&mut stdout(), ReportUnits::default());
+}
+
+#[cfg(all(test, feature = "profiling"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blocks_land_in_the_calling_threads_profiler() {
+        drop(begin_block_with_bandwidth("global::send", 128));
+        drop(begin_block("global::send"));
+
+        let report = report();
+        let send = report
+            .anchors
+            .iter()
+            .find(|anchor| anchor.label == "global::send")
+            .unwrap();
+        assert_eq!((send.hits, send.bytes), (2, 128));
+
+        let other = std::thread::spawn(|| super::report().anchors.len());
+        assert_eq!(other.join().unwrap(), 0);
+    }
+
+    #[profile]
+    fn checksum(data: &[u8]) -> u32 {
+        data.iter().map(|&byte| byte as u32).sum()
+    }
+
+    #[profile(crate = "crate")]
+    fn early_return(skip: bool) -> Option<u32> {
+        if skip {
+            return None;
+        }
+        Some(checksum(b"ab"))
+    }
+
+    #[test]
+    fn profile_attribute_opens_a_block_for_the_function() {
+        assert_eq!(checksum(b"abc"), 294);
+        assert_eq!(early_return(true), None);
+        assert_eq!(early_return(false), Some(195));
+
+        let hits = |label: &str| with(|profiler| profiler.anchor(label).map(|anchor| anchor.hits));
+        assert_eq!(hits("iperf_rs::global::tests::checksum"), Some(2));
+        assert_eq!(hits("iperf_rs::global::tests::early_return"), Some(2));
+    }
+
+    #[test]
+    fn blocks_open_at_thread_exit_are_dropped_quietly() {
+        std::thread::spawn(|| {
+            thread_local! {
+                static LEFT_OPEN: std::cell::RefCell<Option<Block>> = const { std::cell::RefCell::new(None) };
+            }
+            LEFT_OPEN.with(|open| *open.borrow_mut() = Some(begin_block("global::left_open")));
+        })
+        .join()
+        .unwrap();
+    }
+}
@@ -0,0 +1,166 @@
+//! The parent/child relationships between anchors as a weighted graph, for
+//! Graphviz or custom analysis. Unlike the tree and folded outputs, a label
+//! entered under several parents gets one edge per parent, each with the time
+//! and hits spent under that parent.
+
+use crate::report::ratio;
+#[cfg(feature = "std")]
+use crate::report::ScaledMs;
+use crate::{Map, Profiler, TimeSource, ROOT_ANCHOR};
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+#[cfg(feature = "std")]
+use std::io::{self, Write};
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct CallNode {
+    pub label: String,
+    pub hits: u64,
+    pub exclusive_ms: f64,
+    pub inclusive_ms: f64,
+}
+
+/// Hits of `child` under `parent`, both indices into [`CallGraph::nodes`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct CallEdge {
+    pub parent: usize,
+    pub child: usize,
+    pub hits: u64,
+    pub inclusive_ms: f64,
+    /// Share of the whole run's time these hits took.
+    pub percent: f64,
+}
+
+/// Anchors recorded over the whole run; top-level anchors have no incoming edge.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CallGraph {
+    pub nodes: Vec<CallNode>,
+    pub edges: Vec<CallEdge>,
+}
+
+#[cfg(feature = "std")]
+fn write_dot_string<W: Write>(w: &mut W, value: &str) -> io::Result<()> {
+    write!(w, "\"")?;
+    for c in value.chars() {
+        match c {
+            '"' => write!(w, "\\\"")?,
+            '\\' => write!(w, "\\\\")?,
+            '\n' => write!(w, "\\n")?,
+            c => write!(w, "{c}")?,
+        }
+    }
+    write!(w, "\"")
+}
+
+impl CallGraph {
+    /// Writes the graph in Graphviz's DOT language, with each edge labelled by
+    /// its share of the run and its hits.
+    #[cfg(feature = "std")]
+    pub fn write_dot<W: Write>(&self, mut w: W) -> io::Result<()> {
+        writeln!(w, "digraph profile {{")?;
+        writeln!(w, "    node [shape=box];")?;
+        for (index, node) in self.nodes.iter().enumerate() {
+            write!(w, "    n{index} [label=")?;
+            write_dot_string(
+                &mut w,
+                &format!(
+                    "{}\n{} hits, {} ({} w/children)",
+                    node.label,
+                    node.hits,
+                    ScaledMs(node.exclusive_ms),
+                    ScaledMs(node.inclusive_ms)
+                ),
+            )?;
+            writeln!(w, "];")?;
+        }
+        for edge in &self.edges {
+            writeln!(
+                w,
+                "    n{} -> n{} [label=\"{:.2}% ({} hits)\"];",
+                edge.parent, edge.child, edge.percent, edge.hits
+            )?;
+        }
+        writeln!(w, "}}")
+    }
+}
+
+impl<T: TimeSource> Profiler<T> {
+    /// Every anchor with hits and the parent to child edges between them.
+    pub fn call_graph(&self) -> CallGraph {
+        let cpu_freq = self.ticks_per_second();
+        let ticks_to_ms = |ticks: u64| 1000.0 * ticks as f64 / cpu_freq as f64;
+        let total_ticks = self.total_elapsed_ticks();
+        let anchors = self.anchors.borrow();
+
+        let node_of: Map<usize, usize> = anchors
+            .iter()
+            .enumerate()
+            .skip(ROOT_ANCHOR + 1)
+            .filter(|(_, anchor)| anchor.num_hits != 0)
+            .enumerate()
+            .map(|(node, (index, _))| (index, node))
+            .collect();
+
+        let mut graph = CallGraph::default();
+        for (index, anchor) in anchors.iter().enumerate() {
+            let Some(&child) = node_of.get(&index) else {
+                continue;
+            };
+
+            graph.nodes.push(CallNode {
+                label: anchor.label.to_string(),
+                hits: anchor.num_hits,
+                exclusive_ms: ticks_to_ms(
+                    self.without_overhead(anchor.tsc_elapsed_exclusive, anchor.num_hits),
+                ),
+                inclusive_ms: ticks_to_ms(
+                    self.without_overhead(anchor.tsc_elapsed_inclusive, anchor.num_hits),
+                ),
+            });
+            for edge in anchor.parents.iter().filter(|edge| edge.hits != 0) {
+                let Some(&parent) = node_of.get(&edge.index) else {
+                    continue;
+                };
+                let ticks = self.without_overhead(edge.ticks, edge.hits);
+                graph.edges.push(CallEdge {
+                    parent,
+                    child,
+                    hits: edge.hits,
+                    inclusive_ms: ticks_to_ms(ticks),
+                    percent: 100.0 * ratio(ticks as f64, total_ticks as f64),
+                });
+            }
+        }
+        graph
+    }
+}
+
+#[cfg(all(test, feature = "std", feature = "profiling"))]
+mod tests {
+    use crate::tests::started;
+
+    #[test]
+    fn dot_has_an_edge_from_parent_to_child() {
+        let (clock, profiler) = started(1000);
+        profiler.profile("request", || {
+            clock.advance(20);
+            profiler.profile("parse", || clock.advance(30));
+        });
+        clock.advance(50);
+
+        let graph = profiler.call_graph();
+        let node = |label: &str| graph.nodes.iter().position(|node| node.label == label);
+        let (request, parse) = (node("request").unwrap(), node("parse").unwrap());
+        assert_eq!(graph.edges.len(), 1);
+        let edge = &graph.edges[0];
+        assert_eq!((edge.parent, edge.child, edge.hits), (request, parse, 1));
+        assert_eq!(edge.percent, 30.0);
+
+        let mut dot = Vec::new();
+        graph.write_dot(&mut dot).unwrap();
+        let dot = String::from_utf8(dot).unwrap();
+        assert!(dot.starts_with("digraph profile {\n"), "{dot}");
+        let expected = format!("    n{request} -> n{parse} [label=\"30.00% (1 hits)\"];\n");
+        assert!(dot.contains(&expected), "{dot}");
+    }
+}
@@ -0,0 +1,288 @@
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+
+const SUB_BUCKET_BITS: u32 = 2;
+const SUB_BUCKETS: u64 = 1 << SUB_BUCKET_BITS;
+const HISTOGRAM_BUCKETS: usize =
+    (SUB_BUCKETS + (64 - SUB_BUCKET_BITS as u64) * SUB_BUCKETS) as usize;
+
+// Log-linear buckets: every power of two is split into SUB_BUCKETS equal
+// slices, so the relative error of a reported value stays below 25% while the
+// storage is fixed regardless of how many samples are recorded. The exact
+// mean and variance are kept alongside with Welford's algorithm.
+#[derive(Clone, Debug)]
+pub(crate) struct Histogram {
+    buckets: Box<[u64; HISTOGRAM_BUCKETS]>,
+    count: u64,
+    mean: f64,
+    m2: f64,
+}
+
+// Only blocks record samples, and they are compiled out without `profiling`.
+#[cfg_attr(not(feature = "profiling"), allow(dead_code))]
+fn bucket_index(value: u64) -> usize {
+    if value < SUB_BUCKETS {
+        return value as usize;
+    }
+
+    let exponent = 63 - value.leading_zeros();
+    let shift = exponent - SUB_BUCKET_BITS;
+    let sub_bucket = (value >> shift) & (SUB_BUCKETS - 1);
+    (SUB_BUCKETS + shift as u64 * SUB_BUCKETS + sub_bucket) as usize
+}
+
+fn bucket_bounds(index: usize) -> (u64, u64) {
+    let index = index as u64;
+    if index < SUB_BUCKETS {
+        return (index, index);
+    }
+
+    let shift = (index - SUB_BUCKETS) / SUB_BUCKETS;
+    let sub_bucket = (index - SUB_BUCKETS) % SUB_BUCKETS;
+    let lower = (SUB_BUCKETS + sub_bucket) << shift;
+    (lower, lower + ((1u64 << shift) - 1))
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self {
+            buckets: Box::new([0; HISTOGRAM_BUCKETS]),
+            count: 0,
+            mean: 0.0,
+            m2: 0.0,
+        }
+    }
+}
+
+impl Histogram {
+    #[cfg_attr(not(feature = "profiling"), allow(dead_code))]
+    pub(crate) fn record(&mut self, value: u64) {
+        self.buckets[bucket_index(value)] += 1;
+        self.count += 1;
+
+        let value = value as f64;
+        let delta = value - self.mean;
+        self.mean += delta / self.count as f64;
+        self.m2 += delta * (value - self.mean);
+    }
+
+    pub(crate) fn merge(&mut self, other: &Histogram) {
+        for (bucket, &other_bucket) in self.buckets.iter_mut().zip(other.buckets.iter()) {
+            *bucket += other_bucket;
+        }
+
+        // Chan et al.'s pairwise combination of the two running variances.
+        let count = self.count + other.count;
+        if count != 0 {
+            let delta = other.mean - self.mean;
+            let weight = self.count as f64 * other.count as f64 / count as f64;
+            self.mean += delta * other.count as f64 / count as f64;
+            self.m2 += other.m2 + delta * delta * weight;
+        }
+        self.count = count;
+    }
+
+    pub(crate) fn mean(&self) -> Option<f64> {
+        (self.count != 0).then_some(self.mean)
+    }
+
+    // Population standard deviation of the recorded values.
+    pub(crate) fn stddev(&self) -> Option<f64> {
+        (self.count != 0).then(|| sqrt(self.m2 / self.count as f64))
+    }
+
+    pub(crate) fn percentile(&self, quantile: f64) -> Option<u64> {
+        if self.count == 0 {
+            return None;
+        }
+
+        // Rounded up by hand, as `f64::ceil` needs std.
+        let rank = quantile.clamp(0.0, 1.0) * self.count as f64;
+        let target = (rank as u64 + u64::from((rank as u64 as f64) < rank)).max(1);
+        let mut seen = 0;
+        for (index, &bucket) in self.buckets.iter().enumerate() {
+            seen += bucket;
+            if seen >= target {
+                let (lower, upper) = bucket_bounds(index);
+                return Some(lower + (upper - lower) / 2);
+            }
+        }
+        None
+    }
+}
+
+#[cfg(feature = "std")]
+fn sqrt(value: f64) -> f64 {
+    value.sqrt()
+}
+
+// `f64::sqrt` needs std. Halving the exponent gets within a few percent, and
+// Newton's method doubles the correct digits with every step from there.
+#[cfg(not(feature = "std"))]
+fn sqrt(value: f64) -> f64 {
+    if value <= 0.0 {
+        return 0.0;
+    }
+    let mut root = f64::from_bits((value.to_bits() >> 1) + (1023 << 51));
+    for _ in 0..6 {
+        root = (root + value / root) / 2.0;
+    }
+    root
+}
+
+/// Intervals whose throughput fell in `[min_bits_per_second, max_bits_per_second]`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ThroughputBucket {
+    pub min_bits_per_second: u64,
+    pub max_bits_per_second: u64,
+    pub intervals: u64,
+}
+
+// One bucket per power of two of bits per second, plus one for stalled intervals.
+#[derive(Clone, Debug)]
+pub(crate) struct ThroughputHistogram {
+    buckets: [u64; 65],
+}
+
+impl Default for ThroughputHistogram {
+    fn default() -> Self {
+        Self { buckets: [0; 65] }
+    }
+}
+
+impl ThroughputHistogram {
+    pub(crate) fn record(&mut self, bits_per_second: f64) {
+        let value = bits_per_second as u64;
+        self.buckets[(64 - value.leading_zeros()) as usize] += 1;
+    }
+
+    pub(crate) fn buckets(&self) -> Vec<ThroughputBucket> {
+        self.buckets
+            .iter()
+            .enumerate()
+            .filter(|(_, &intervals)| intervals != 0)
+            .map(|(index, &intervals)| {
+                let (min_bits_per_second, max_bits_per_second) = match index {
+                    0 => (0, 0),
+                    64 => (1 << 63, u64::MAX),
+                    index => (1 << (index - 1), (1 << index) - 1),
+                };
+                ThroughputBucket {
+                    min_bits_per_second,
+                    max_bits_per_second,
+                    intervals,
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(feature = "profiling")]
+    use crate::tests::started;
+
+    #[test]
+    fn buckets_cover_their_values() {
+        for value in [0, 1, 3, 4, 5, 7, 8, 100, 1 << 20, u64::MAX] {
+            let (lower, upper) = bucket_bounds(bucket_index(value));
+            assert!(
+                lower <= value && value <= upper,
+                "{value}: {lower}..={upper}"
+            );
+        }
+        assert!(bucket_index(u64::MAX) < HISTOGRAM_BUCKETS);
+    }
+
+    #[test]
+    fn percentiles_of_a_uniform_distribution() {
+        let mut histogram = Histogram::default();
+        assert_eq!(histogram.percentile(0.5), None);
+        for value in 1..=1000 {
+            histogram.record(value);
+        }
+
+        for (quantile, expected) in [(0.50, 500), (0.90, 900), (0.99, 990)] {
+            let reported = histogram.percentile(quantile).unwrap();
+            let (lower, upper) = bucket_bounds(bucket_index(expected));
+            assert!(
+                lower <= reported && reported <= upper,
+                "p{quantile}: {reported}"
+            );
+        }
+    }
+
+    #[test]
+    fn a_slow_tail_moves_only_the_high_percentiles() {
+        let mut histogram = Histogram::default();
+        for _ in 0..95 {
+            histogram.record(10);
+        }
+        for _ in 0..5 {
+            histogram.record(10_000);
+        }
+
+        assert_eq!(histogram.percentile(0.5), Some(10));
+        assert_eq!(histogram.percentile(0.9), Some(10));
+        let p99 = histogram.percentile(0.99).unwrap();
+        assert!((8192..=12287).contains(&p99), "{p99}");
+    }
+
+    #[test]
+    fn square_roots_match_the_std_ones() {
+        for value in [0.0, 1e-9, 0.25, 2.0, 4.0, 1e6, 1.5e18] {
+            let (root, expected) = (sqrt(value), value.sqrt());
+            assert!(
+                (root - expected).abs() <= expected * 1e-15,
+                "{value}: {root}"
+            );
+        }
+    }
+
+    #[test]
+    fn mean_and_stddev_survive_a_merge() {
+        let values = [2, 4, 4, 4, 5, 5, 7, 9];
+        let (mut first, mut second) = (Histogram::default(), Histogram::default());
+        for (i, &value) in values.iter().enumerate() {
+            if i % 3 == 0 { &mut first } else { &mut second }.record(value);
+        }
+        first.merge(&second);
+
+        assert!((first.mean().unwrap() - 5.0).abs() < 1e-9);
+        assert!((first.stddev().unwrap() - 2.0).abs() < 1e-9);
+        assert_eq!(Histogram::default().stddev(), None);
+    }
+
+    #[cfg(feature = "profiling")]
+    #[test]
+    fn throughput_histogram_separates_bursts_from_stalls() {
+        let (clock, mut profiler) = started(1000);
+        profiler.enable_throughput_histograms(true);
+        // 8 kbit/s and 8 Mbit/s intervals, then one without any sends.
+        for bytes in [1000, 1_000_000, 1000, 1_000_000, 0] {
+            if bytes != 0 {
+                let block = profiler.begin_block_with_bandwidth("send", bytes);
+                clock.advance(10);
+                drop(block);
+            }
+            clock.advance(1000 - if bytes != 0 { 10 } else { 0 });
+            profiler.report_interval();
+        }
+
+        let bucket = |min_bits_per_second, max_bits_per_second, intervals| ThroughputBucket {
+            min_bits_per_second,
+            max_bits_per_second,
+            intervals,
+        };
+        assert_eq!(
+            profiler.throughput_histogram("send").unwrap(),
+            [
+                bucket(0, 0, 1),
+                bucket(4096, 8191, 2),
+                bucket(4_194_304, 8_388_607, 2)
+            ]
+        );
+        assert_eq!(profiler.throughput_histogram("recv"), None);
+    }
+}
@@ -1,58 +1,100 @@
-use nix::unistd::SysconfVar;
-use std::arch::x86_64::_rdtsc;
-use std::collections::HashMap;
-use std::fs::File;
-use std::io::stdout;
-use std::io::Write;
-use std::mem::MaybeUninit;
-use std::os::fd::{AsRawFd, FromRawFd};
-use std::ptr::null_mut;
-
-#[inline]
-fn read_cpu_timer() -> u64 {
-    unsafe { _rdtsc() }
-}
-
-#[inline]
-fn get_os_clock_frequency() -> u64 {
-    nix::unistd::sysconf(SysconfVar::CLK_TCK).unwrap().unwrap() as u64 * 10_000
-}
-
-#[inline]
-fn read_os_timer() -> u64 {
-    let mut value = nix::libc::timeval {
-        tv_sec: 0,
-        tv_usec: 0,
-    };
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
 
-    let call_res = unsafe { nix::libc::gettimeofday(&mut value, null_mut()) };
-    assert_ne!(call_res, -1, "gettimeofday() failed");
-    get_os_clock_frequency() * value.tv_sec as u64 + value.tv_usec as u64
-}
+extern crate alloc;
 
-#[inline]
-fn get_cpu_frequency() -> u64 {
-    let ms_to_wait = 100u64;
-    let os_freq = get_os_clock_frequency();
+#[cfg(feature = "std")]
+mod builder;
+mod datagram;
+#[cfg(feature = "std")]
+mod export;
+#[cfg(feature = "std")]
+pub mod global;
+mod graph;
+mod histogram;
+#[cfg(feature = "std")]
+mod limiter;
+#[cfg(feature = "tui")]
+mod live;
+mod macros;
+#[cfg(feature = "std")]
+mod output;
+#[cfg(feature = "std")]
+mod registry;
+mod report;
+#[cfg(feature = "serde")]
+mod saved;
+#[cfg(feature = "std")]
+mod shared;
+mod snapshot;
+mod timer;
+#[cfg(feature = "tracing")]
+mod tracing_layer;
 
-    let cpu_start = read_cpu_timer();
-    let os_start = read_os_timer();
-    let mut os_elapsed = 0u64;
-    let os_wait_time = os_freq * ms_to_wait / 1000;
+// Lets `#[profile]`, which names the crate as `::iperf_rs`, be used in here too.
+extern crate self as iperf_rs;
 
-    while os_elapsed < os_wait_time {
-        os_elapsed = read_os_timer() - os_start;
-    }
+use alloc::boxed::Box;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::cell::{Cell, RefCell};
+use core::fmt;
+use core::hint::black_box;
+use core::marker::PhantomData;
+use core::time::Duration;
+use histogram::{Histogram, ThroughputHistogram};
+#[cfg(feature = "std")]
+use output::ProfileOutput;
+use report::{ratio, ROOT_LABEL};
+#[cfg(feature = "std")]
+use std::time::SystemTime;
+
+// Labels are hashed with std; without it they live in ordered maps instead.
+#[cfg(not(feature = "std"))]
+pub(crate) use alloc::collections::{BTreeMap as Map, BTreeSet as Set};
+#[cfg(feature = "std")]
+pub(crate) use std::collections::{HashMap as Map, HashSet as Set};
 
-    let cpu_end = read_cpu_timer();
-    let cpu_elapsed = cpu_end - cpu_start;
+#[cfg(feature = "std")]
+pub use builder::ProfilerBuilder;
+pub use datagram::{DatagramReport, DatagramStats};
+#[cfg(feature = "std")]
+pub use export::FoldedSamples;
+pub use graph::{CallEdge, CallGraph, CallNode};
+pub use histogram::ThroughputBucket;
+#[cfg(feature = "std")]
+pub use limiter::BandwidthLimiter;
+#[cfg(feature = "tui")]
+pub use live::LiveView;
+#[cfg(feature = "std")]
+pub use registry::ProfilerRegistry;
+pub use report::{AnchorReport, PercentBase, ProfileReport, ReportUnits, SortBy};
+#[cfg(feature = "std")]
+pub use shared::{SharedProfiler, ThreadProfiler};
+pub use snapshot::{diff, AnchorDelta, AnchorSnapshot, ProfileSnapshot};
+pub use timer::TimeSource;
+#[cfg(feature = "std")]
+pub use timer::{
+    cpu_timer, estimate_cpu_hz, CalibrationClock, CpuTimeSource, InstantTimeSource,
+    DEFAULT_CALIBRATION,
+};
+#[cfg(feature = "tracing")]
+pub use tracing_layer::ProfilerLayer;
 
-    assert_ne!(os_elapsed, 0, "os elapsed is zero!");
-    os_freq * cpu_elapsed / os_elapsed
+const DEFAULT_PROFILE_CAPACITY: usize = 4096;
+const ROOT_ANCHOR: usize = 0;
+const OVERHEAD_LABEL: &str = "(overhead)";
+const OVERHEAD_SAMPLES: usize = 1000;
+const NOT_STARTED_MESSAGE: &str = "Performance report: profiler was not started";
+
+fn ticks_to_nanos(ticks: u64, cpu_freq: u64) -> u64 {
+    (ticks as u128 * 1_000_000_000 / cpu_freq as u128).min(u64::MAX as u128) as u64
 }
 
-const MAX_PROFILE_ANCHORS: usize = 4096;
-const PROFILE_OUTPUT_ENV: &str = "PROFILE_OUT";
+fn ticks_to_duration(ticks: u64, cpu_freq: u64) -> Duration {
+    Duration::from_nanos(ticks_to_nanos(ticks, cpu_freq))
+}
 
 #[derive(Default)]
 pub struct ProfileAnchor {
@@ -60,187 +102,2673 @@ pub struct ProfileAnchor {
     tsc_elapsed_inclusive: u64,
     num_hits: u64,
     bytes_processed: u64,
-    label: String,
+    tx_bytes: u64,
+    rx_bytes: u64,
+    // None until the first timed hit, which warmup and sampling may delay.
+    min_tsc: Option<u64>,
+    max_tsc: u64,
+    last_tsc: u64,
+    over_budget: u64,
+    // Set once the first overrun was printed; later ones are only counted.
+    budget_warned: bool,
+    warmup_hits: u64,
+    histogram: Option<Histogram>,
+    parent_index: usize,
+    parents: Vec<ParentEdge>,
+    // Times one hit in this many; 0 and 1 time every hit.
+    sample_every: u64,
+    // Hits since the last timed one, counting up to `sample_every`.
+    sample_phase: u64,
+    label: Arc<str>,
+}
+
+// The hits an anchor took under one parent and how long they took, for the
+// call graph.
+#[derive(Clone, Copy, Debug, Default)]
+struct ParentEdge {
+    index: usize,
+    hits: u64,
+    ticks: u64,
+}
+
+impl ProfileAnchor {
+    // How many hits the next block stands for: `sample_every` for a timed hit
+    // and 0 for one that is only counted.
+    #[cfg(feature = "profiling")]
+    #[inline]
+    fn next_sample_weight(&mut self) -> u64 {
+        if self.sample_every <= 1 {
+            return 1;
+        }
+        let timed = self.sample_phase == 0;
+        self.sample_phase = (self.sample_phase + 1) % self.sample_every;
+        if timed {
+            self.sample_every
+        } else {
+            0
+        }
+    }
+
+    fn merge(&mut self, other: &ProfileAnchor, parent_map: &Map<usize, usize>) {
+        let map_parent = |index: &usize| parent_map.get(index).copied().unwrap_or(ROOT_ANCHOR);
+        self.min_tsc = self.min_tsc.into_iter().chain(other.min_tsc).min();
+        self.max_tsc = self.max_tsc.max(other.max_tsc);
+        self.last_tsc = other.last_tsc;
+        self.tsc_elapsed_exclusive += other.tsc_elapsed_exclusive;
+        self.tsc_elapsed_inclusive += other.tsc_elapsed_inclusive;
+        self.num_hits += other.num_hits;
+        self.bytes_processed += other.bytes_processed;
+        self.tx_bytes += other.tx_bytes;
+        self.rx_bytes += other.rx_bytes;
+        self.over_budget += other.over_budget;
+        self.warmup_hits += other.warmup_hits;
+        if let Some(other_histogram) = &other.histogram {
+            self.histogram
+                .get_or_insert_with(Histogram::default)
+                .merge(other_histogram);
+        }
+        self.parent_index = map_parent(&other.parent_index);
+        // Edges zeroed by a flush or `reset_anchor` may lead to parents that
+        // weren't merged; they carry nothing anyway.
+        for other_edge in other.parents.iter().filter(|edge| edge.hits != 0) {
+            let index = map_parent(&other_edge.index);
+            match self.parents.iter_mut().find(|edge| edge.index == index) {
+                Some(edge) => {
+                    edge.hits += other_edge.hits;
+                    edge.ticks += other_edge.ticks;
+                }
+                None => self.parents.push(ParentEdge {
+                    index,
+                    ..*other_edge
+                }),
+            }
+        }
+    }
+}
+
+/// A cheap snapshot of one anchor's counters, see [`Profiler::anchor`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct AnchorStats {
+    pub label: Arc<str>,
+    pub hits: u64,
+    pub exclusive_ticks: u64,
+    pub inclusive_ticks: u64,
+    pub exclusive_ms: f64,
+    pub inclusive_ms: f64,
+    pub bytes: u64,
+    pub tx_bytes: u64,
+    pub rx_bytes: u64,
+    pub over_budget: u64,
+    pub warmup_hits: u64,
+}
+
+/// A label registered up front with [`Profiler::register_anchor`], so hot blocks
+/// can skip the label lookup. Only valid until the next `start` or `reset`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct AnchorId {
+    index: usize,
+    generation: u64,
+}
+
+/// Which way a block's bytes went, for separate sender and receiver totals.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    Tx,
+    Rx,
+}
+
+/// A broken accounting invariant found by [`Profiler::validate`].
+#[cfg(debug_assertions)]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Violation {
+    /// The exclusive times of all anchors add up to more than the whole run.
+    ExclusiveSumExceedsTotal {
+        exclusive_ticks: u128,
+        total_ticks: u64,
+    },
+    /// The anchor's exclusive time alone is longer than the whole run.
+    ExclusiveExceedsTotal {
+        label: Arc<str>,
+        exclusive_ticks: u64,
+    },
+    InclusiveBelowExclusive {
+        label: Arc<str>,
+        exclusive_ticks: u64,
+        inclusive_ticks: u64,
+    },
+    ParentOutOfRange {
+        label: Arc<str>,
+        parent_index: usize,
+    },
+}
+
+/// An open block, recorded when dropped. It updates its profiler on drop, so it
+/// must stay on the thread that began it:
+///
+/// ```compile_fail
+/// let profiler = iperf_rs::Profiler::new();
+/// std::thread::scope(|scope| {
+///     let block = profiler.begin_block("moved");
+///     scope.spawn(move || drop(block));
+/// });
+/// ```
+#[cfg(feature = "profiling")]
+pub struct ProfileBlock<
+    'a,
+    #[cfg(feature = "std")] T: TimeSource = CpuTimeSource,
+    #[cfg(not(feature = "std"))] T: TimeSource,
+> {
+    open: OpenBlock,
+    profiler: &'a Profiler<T>,
+    // Keeps the block !Send and !Sync even if the profiler itself were shareable.
+    _not_send: PhantomData<*const ()>,
 }
 
-pub struct ProfileBlock {
+#[cfg(not(feature = "profiling"))]
+pub struct ProfileBlock<
+    'a,
+    #[cfg(feature = "std")] T: TimeSource = CpuTimeSource,
+    #[cfg(not(feature = "std"))] T: TimeSource,
+> {
+    profiler: PhantomData<&'a Profiler<T>>,
+    _not_send: PhantomData<*const ()>,
+}
+
+// What an open block remembers apart from the profiler it records into, so a
+// block can be detached from its borrow and ended later, like the blocks of the
+// per-thread global profiler.
+#[cfg(feature = "profiling")]
+#[derive(Clone, Copy)]
+pub(crate) struct OpenBlock {
     start_tsc: u64,
+    paused_tsc: u64,
+    old_tsc_exclusive: u64,
     old_tsc_inclusive: u64,
     anchor_index: usize,
     parent_index: usize,
     bytes_processed: u64,
-    label: String,
-    profiler_addr: usize,
+    direction: Option<Direction>,
+    budget_tsc: u64,
+    // Hits this block's time stands for under sampling; 0 when it is only counted.
+    weight: u64,
+    // The enclosing block's weight, which scales what this block takes off its
+    // exclusive time.
+    parent_weight: u64,
+}
+
+#[cfg(all(feature = "std", not(feature = "profiling")))]
+#[derive(Clone, Copy)]
+pub(crate) struct OpenBlock;
+
+#[cfg(feature = "profiling")]
+impl<'a, T: TimeSource> ProfileBlock<'a, T> {
+    pub fn new(anchor_index: usize, bytes_processed: u64, profiler: &'a Profiler<T>) -> Self {
+        Self {
+            open: OpenBlock::begin(anchor_index, bytes_processed, profiler),
+            profiler,
+            _not_send: PhantomData,
+        }
+    }
+
+    // A block that records nothing, handed out when the label can't get an anchor.
+    fn noop(profiler: &'a Profiler<T>) -> Self {
+        Self {
+            open: OpenBlock::NOOP,
+            profiler,
+            _not_send: PhantomData,
+        }
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.open.is_recording()
+    }
+
+    fn with_budget(mut self, budget: Duration) -> Self {
+        if self.is_recording() {
+            let cpu_freq = self.profiler.ticks_per_second() as u128;
+            let budget_tsc = (budget.as_nanos() * cpu_freq).div_ceil(1_000_000_000);
+            self.open.budget_tsc = budget_tsc.max(1) as u64;
+        }
+        self
+    }
+
+    fn with_direction(mut self, direction: Direction) -> Self {
+        self.open.direction = Some(direction);
+        self
+    }
+
+    // Releases the borrow of the profiler; the block is recorded once the
+    // returned state is ended against the same profiler.
+    #[cfg(feature = "std")]
+    pub(crate) fn detach(self) -> OpenBlock {
+        let open = self.open;
+        core::mem::forget(self);
+        open
+    }
+}
+
+#[cfg(not(feature = "profiling"))]
+impl<'a, T: TimeSource> ProfileBlock<'a, T> {
+    #[inline(always)]
+    pub fn new(_anchor_index: usize, _bytes_processed: u64, _profiler: &'a Profiler<T>) -> Self {
+        Self {
+            profiler: PhantomData,
+            _not_send: PhantomData,
+        }
+    }
+
+    #[inline(always)]
+    fn noop(_profiler: &'a Profiler<T>) -> Self {
+        Self {
+            profiler: PhantomData,
+            _not_send: PhantomData,
+        }
+    }
+
+    pub fn is_recording(&self) -> bool {
+        false
+    }
+
+    #[inline(always)]
+    fn with_budget(self, _budget: Duration) -> Self {
+        self
+    }
+
+    #[inline(always)]
+    fn with_direction(self, _direction: Direction) -> Self {
+        self
+    }
+
+    #[cfg(feature = "std")]
+    #[inline(always)]
+    pub(crate) fn detach(self) -> OpenBlock {
+        OpenBlock
+    }
+}
+
+#[cfg(feature = "profiling")]
+impl<T: TimeSource> Drop for ProfileBlock<'_, T> {
+    fn drop(&mut self) {
+        self.open.end(self.profiler);
+    }
 }
 
-impl ProfileBlock {
-    pub fn new(
+#[cfg(feature = "profiling")]
+impl OpenBlock {
+    const NOOP: OpenBlock = OpenBlock {
+        start_tsc: 0,
+        paused_tsc: 0,
+        old_tsc_exclusive: 0,
+        old_tsc_inclusive: 0,
+        parent_index: ROOT_ANCHOR,
+        anchor_index: ROOT_ANCHOR,
+        bytes_processed: 0,
+        direction: None,
+        budget_tsc: 0,
+        weight: 1,
+        parent_weight: 1,
+    };
+
+    fn begin<T: TimeSource>(
         anchor_index: usize,
-        label: &str,
         bytes_processed: u64,
-        profiler: *mut Profiler,
+        profiler: &Profiler<T>,
     ) -> Self {
-        let profiler_mut = unsafe { profiler.as_mut() }.unwrap();
-        let old_tsc_inclusive = profiler_mut.anchors[anchor_index].tsc_elapsed_inclusive;
-        let parent_index = profiler_mut.parent_index;
-        profiler_mut.parent_index = anchor_index;
+        let (old_tsc_exclusive, old_tsc_inclusive, weight) = {
+            let anchor = &mut profiler.anchors.borrow_mut()[anchor_index];
+            let weight = anchor.next_sample_weight();
+            (
+                anchor.tsc_elapsed_exclusive,
+                anchor.tsc_elapsed_inclusive,
+                weight,
+            )
+        };
+        let parent_index = profiler.parent_index.replace(anchor_index);
+        let parent_weight = profiler.parent_weight.replace(weight);
+        if weight == 0 {
+            // A hit skipped by sampling: it counts towards the hits and bytes
+            // without reading the timer. Blocks opened inside it take nothing off
+            // its time, since the timed hits' scaled time already covers them.
+            return Self {
+                anchor_index,
+                parent_index,
+                bytes_processed,
+                weight: 0,
+                parent_weight,
+                ..Self::NOOP
+            };
+        }
+        let start_tsc = profiler.time_source.now_ticks();
 
         Self {
-            start_tsc: read_cpu_timer(),
+            start_tsc,
+            paused_tsc: profiler.paused_ticks_until(start_tsc),
+            old_tsc_exclusive,
             old_tsc_inclusive,
             parent_index,
             anchor_index,
-            label: label.to_string(),
             bytes_processed,
-            profiler_addr: profiler as usize,
+            direction: None,
+            budget_tsc: 0,
+            weight,
+            parent_weight,
         }
     }
-}
 
-impl Drop for ProfileBlock {
-    fn drop(&mut self) {
-        let profiler_mut =
-            unsafe { (self.profiler_addr as *const Profiler).cast_mut().as_mut() }.unwrap();
-        profiler_mut.parent_index = self.parent_index;
+    fn is_recording(&self) -> bool {
+        self.anchor_index != ROOT_ANCHOR
+    }
+
+    pub(crate) fn end<T: TimeSource>(&self, profiler: &Profiler<T>) {
+        if !self.is_recording() {
+            return;
+        }
+        profiler.parent_index.set(self.parent_index);
+        profiler.parent_weight.set(self.parent_weight);
+        if self.weight == 0 {
+            self.count_untimed(profiler);
+            return;
+        }
+
+        let mut anchors = profiler.anchors.borrow_mut();
+        let anchor = &mut anchors[self.anchor_index];
+
+        let end_tsc = profiler.time_source.now_ticks();
+        let Some(elapsed) = end_tsc.checked_sub(self.start_tsc) else {
+            // Undo what the children took off this hit's exclusive time, as
+            // for a warmup hit.
+            anchor.tsc_elapsed_exclusive = self.old_tsc_exclusive;
+            let discarded = &profiler.discarded_samples;
+            discarded.set(discarded.get() + 1);
+            return;
+        };
+        let paused = profiler.paused_ticks_until(end_tsc) - self.paused_tsc;
+        let elapsed = elapsed.saturating_sub(paused);
+
+        let mut ended_label = None;
+        let mut overrun_label = None;
+        if anchor.warmup_hits < profiler.warmup {
+            // Drop this hit, including what its children took off the exclusive
+            // time. Its time still comes off the parent's exclusive time below.
+            anchor.warmup_hits += 1;
+            anchor.tsc_elapsed_exclusive = self.old_tsc_exclusive;
+        } else {
+            let over_budget = self.record(anchor, elapsed, profiler);
+            if profiler.on_block_end.is_some() {
+                ended_label = Some(anchor.label.clone());
+            }
+            if over_budget && profiler.panics_over_budget() {
+                overrun_label = Some(anchor.label.clone());
+            }
+        }
+
+        if self.parent_index != ROOT_ANCHOR {
+            // What the anchor was credited, including the hits sampling skipped,
+            // whose time the parent's exclusive time still holds, scaled like the
+            // parent's own time when that is sampled too.
+            let weighted = elapsed
+                .saturating_mul(self.weight)
+                .saturating_mul(self.parent_weight);
+            let parent_anchor = &mut anchors[self.parent_index];
+            parent_anchor.tsc_elapsed_exclusive =
+                parent_anchor.tsc_elapsed_exclusive.wrapping_sub(weighted);
+        }
+
+        // Released first so the callback may open blocks of its own.
+        drop(anchors);
+        if let (Some(callback), Some(label)) = (&profiler.on_block_end, ended_label) {
+            callback(&label, elapsed, self.bytes_processed);
+        }
+        if let Some(label) = overrun_label {
+            panic!("{}", self.overrun_message(&label, elapsed, profiler));
+        }
+    }
 
-        let anchor = &mut profiler_mut.anchors[self.anchor_index];
+    fn overrun_message<T: TimeSource>(
+        &self,
+        label: &str,
+        elapsed: u64,
+        profiler: &Profiler<T>,
+    ) -> String {
+        let cpu_freq = profiler.ticks_per_second();
+        alloc::format!(
+            "{label} took {:?}, over its {:?} budget",
+            ticks_to_duration(elapsed, cpu_freq),
+            ticks_to_duration(self.budget_tsc, cpu_freq)
+        )
+    }
 
-        let elapsed = read_cpu_timer() - self.start_tsc;
+    fn count_untimed<T: TimeSource>(&self, profiler: &Profiler<T>) {
+        let mut anchors = profiler.anchors.borrow_mut();
+        let anchor = &mut anchors[self.anchor_index];
+        if anchor.warmup_hits < profiler.warmup {
+            anchor.warmup_hits += 1;
+        } else {
+            anchor.num_hits += 1;
+            self.add_bytes(anchor);
+        }
+    }
 
-        anchor.tsc_elapsed_exclusive += elapsed;
-        anchor.tsc_elapsed_inclusive = self.old_tsc_inclusive + elapsed;
+    fn add_bytes(&self, anchor: &mut ProfileAnchor) {
         anchor.bytes_processed += self.bytes_processed;
-        anchor.num_hits += 1;
-        anchor.label = self.label.clone();
+        match self.direction {
+            Some(Direction::Tx) => anchor.tx_bytes += self.bytes_processed,
+            Some(Direction::Rx) => anchor.rx_bytes += self.bytes_processed,
+            None => {}
+        }
+    }
 
-        let parent_anchor = &mut profiler_mut.anchors[self.parent_index];
-        parent_anchor.tsc_elapsed_exclusive -= elapsed;
+    // Returns whether the hit ran past its budget.
+    fn record<T: TimeSource>(
+        &self,
+        anchor: &mut ProfileAnchor,
+        elapsed: u64,
+        profiler: &Profiler<T>,
+    ) -> bool {
+        // A sampled hit stands for the ones skipped since, so its time is
+        // scaled up to extrapolate theirs.
+        let weighted = elapsed.saturating_mul(self.weight);
+        // A parent's exclusive time is reduced by its children before the parent
+        // itself closes and adds its own elapsed time, so it may dip below zero in
+        // between. Wrapping arithmetic makes the final value exact either way.
+        anchor.tsc_elapsed_exclusive = anchor.tsc_elapsed_exclusive.wrapping_add(weighted);
+        // Recursive blocks all restore the inclusive time captured when they opened,
+        // so the outermost one to close overwrites the inner ones instead of
+        // counting the recursion twice.
+        anchor.tsc_elapsed_inclusive = self.old_tsc_inclusive + weighted;
+        self.add_bytes(anchor);
+        anchor.min_tsc = Some(anchor.min_tsc.map_or(elapsed, |min| min.min(elapsed)));
+        anchor.max_tsc = anchor.max_tsc.max(elapsed);
+        anchor.last_tsc = elapsed;
+        if profiler.histograms_enabled {
+            anchor
+                .histogram
+                .get_or_insert_with(Histogram::default)
+                .record(elapsed);
+        }
+        anchor.num_hits += 1;
+        let over_budget = self.budget_tsc != 0 && elapsed > self.budget_tsc;
+        if over_budget {
+            anchor.over_budget += 1;
+            // Only the first overrun of an anchor is printed, so a hot block
+            // that regressed doesn't flood stderr and slow down even more.
+            if !anchor.budget_warned && !profiler.panics_over_budget() {
+                anchor.budget_warned = true;
+                #[cfg(feature = "std")]
+                eprintln!(
+                    "warning: {}, further overruns are only counted",
+                    self.overrun_message(&anchor.label, elapsed, profiler)
+                );
+            }
+        }
+        if self.parent_index != self.anchor_index {
+            anchor.parent_index = self.parent_index;
+            match anchor
+                .parents
+                .iter_mut()
+                .find(|edge| edge.index == self.parent_index)
+            {
+                Some(edge) => {
+                    edge.hits += self.weight;
+                    edge.ticks += weighted;
+                }
+                None => anchor.parents.push(ParentEdge {
+                    index: self.parent_index,
+                    hits: self.weight,
+                    ticks: weighted,
+                }),
+            }
+        }
+        over_budget
     }
 }
 
-pub struct Profiler {
-    anchors: [ProfileAnchor; MAX_PROFILE_ANCHORS],
-    label_to_index: HashMap<String, usize>,
-    parent_index: usize,
-    start_tsc: u64,
-    end_tsc: u64,
-    log_file: File,
+// The counters a report is made of, as of some earlier point in the run.
+#[derive(Clone, Copy, Default)]
+struct AnchorCounts {
+    hits: u64,
+    exclusive: u64,
+    inclusive: u64,
+    bytes: u64,
+    tx_bytes: u64,
+    rx_bytes: u64,
 }
 
-fn empty_anchores() -> [ProfileAnchor; MAX_PROFILE_ANCHORS] {
-    let mut anchor_array: [MaybeUninit<ProfileAnchor>; MAX_PROFILE_ANCHORS] =
-        unsafe { MaybeUninit::uninit().assume_init() };
+impl AnchorCounts {
+    fn of(anchor: &ProfileAnchor) -> Self {
+        Self {
+            hits: anchor.num_hits,
+            exclusive: anchor.tsc_elapsed_exclusive,
+            inclusive: anchor.tsc_elapsed_inclusive,
+            bytes: anchor.bytes_processed,
+            tx_bytes: anchor.tx_bytes,
+            rx_bytes: anchor.rx_bytes,
+        }
+    }
 
-    for v in anchor_array.iter_mut() {
-        *v = MaybeUninit::new(ProfileAnchor::default());
+    fn delta(self, anchor: &ProfileAnchor) -> Self {
+        Self {
+            hits: anchor.num_hits - self.hits,
+            exclusive: anchor.tsc_elapsed_exclusive.wrapping_sub(self.exclusive),
+            inclusive: anchor.tsc_elapsed_inclusive - self.inclusive,
+            bytes: anchor.bytes_processed - self.bytes,
+            tx_bytes: anchor.tx_bytes - self.tx_bytes,
+            rx_bytes: anchor.rx_bytes - self.rx_bytes,
+        }
     }
+}
 
-    unsafe { std::mem::transmute(anchor_array) }
+#[derive(Default)]
+struct IntervalMark {
+    tsc: u64,
+    paused_tsc: u64,
+    anchors: Vec<AnchorCounts>,
 }
 
-impl Profiler {
-    pub fn new() -> Self {
-        let profile_output = if let Ok(value) = std::env::var(PROFILE_OUTPUT_ENV) {
-            File::create(value).unwrap()
-        } else {
-            unsafe { File::from_raw_fd(stdout().as_raw_fd()) }
-        };
+fn record_throughput(histograms: &mut Map<String, ThroughputHistogram>, report: &ProfileReport) {
+    let seconds = report.total_ms / 1000.0;
+    for (label, histogram) in histograms.iter_mut() {
+        if !report.anchors.iter().any(|anchor| &anchor.label == label) {
+            histogram.record(0.0);
+        }
+    }
+    for anchor in report.anchors.iter().filter(|anchor| anchor.bytes != 0) {
+        histograms
+            .entry(anchor.label.clone())
+            .or_default()
+            .record(8.0 * ratio(anchor.bytes as f64, seconds));
+    }
+}
+
+type BlockEndCallback = Box<dyn Fn(&str, u64, u64) + Send>;
+
+// The hardware counter is the default time source; without std there is none
+// and the caller always brings one.
+pub struct Profiler<
+    #[cfg(feature = "std")] T: TimeSource = CpuTimeSource,
+    #[cfg(not(feature = "std"))] T: TimeSource,
+> {
+    time_source: T,
+    // 0 until the rate is first needed.
+    cpu_freq: Cell<u64>,
+    anchors: RefCell<Vec<ProfileAnchor>>,
+    label_to_index: RefCell<Map<Arc<str>, usize>>,
+    parent_index: Cell<usize>,
+    // The weight of the innermost open block, see `OpenBlock::parent_weight`.
+    parent_weight: Cell<u64>,
+    // None until `start`, and until `stop` for the end, so a counter that reads
+    // zero can't be mistaken for either.
+    start_tsc: Option<u64>,
+    end_tsc: Option<u64>,
+    #[cfg(feature = "std")]
+    log_file: ProfileOutput,
+    report_units: ReportUnits,
+    histograms_enabled: bool,
+    capacity: usize,
+    capacity_warned: Cell<bool>,
+    sort_by: SortBy,
+    top_n: Option<usize>,
+    paused_since: Cell<Option<u64>>,
+    paused_tsc: Cell<u64>,
+    block_overhead: u64,
+    discarded_samples: Cell<u64>,
+    interval: IntervalMark,
+    #[cfg(feature = "std")]
+    color: bool,
+    #[cfg(feature = "std")]
+    print_on_drop: bool,
+    warmup: u64,
+    omit: Duration,
+    omit_mark: RefCell<Option<IntervalMark>>,
+    omit_pending: Cell<bool>,
+    #[cfg(feature = "std")]
+    started_at: Option<SystemTime>,
+    #[cfg(feature = "std")]
+    ended_at: Option<SystemTime>,
+    smoothing_alpha: Option<f64>,
+    smoothed_throughput: Map<String, f64>,
+    throughput_histograms: Option<Map<String, ThroughputHistogram>>,
+    // Bumped by every reset, which invalidates all handed out `AnchorId`s.
+    generation: u64,
+    precision: Option<usize>,
+    on_block_end: Option<BlockEndCallback>,
+    ignored: Set<Box<str>>,
+    sampling: Map<Box<str>, u64>,
+    // Set when appending to a `PROFILE_OUT` file that earlier runs wrote to.
+    #[cfg(feature = "std")]
+    separate_reports: bool,
+    datagrams: RefCell<DatagramStats>,
+    panic_over_budget: bool,
+}
+
+fn empty_anchores() -> Vec<ProfileAnchor> {
+    vec![ProfileAnchor::default()]
+}
 
+impl<T: TimeSource> Profiler<T> {
+    // A profiler with every setting at its default. With std it prints to
+    // stdout until the caller picks an output.
+    fn with_defaults(time_source: T) -> Self {
         Profiler {
-            anchors: empty_anchores(),
-            label_to_index: HashMap::new(),
-            log_file: profile_output,
-            parent_index: 0,
-            start_tsc: 0,
-            end_tsc: 0,
+            time_source,
+            cpu_freq: Cell::new(0),
+            anchors: RefCell::new(empty_anchores()),
+            label_to_index: RefCell::new(Map::new()),
+            #[cfg(feature = "std")]
+            log_file: ProfileOutput::default(),
+            parent_index: Cell::new(ROOT_ANCHOR),
+            parent_weight: Cell::new(1),
+            start_tsc: None,
+            end_tsc: None,
+            report_units: ReportUnits::default(),
+            histograms_enabled: false,
+            capacity: DEFAULT_PROFILE_CAPACITY,
+            capacity_warned: Cell::new(false),
+            sort_by: SortBy::default(),
+            top_n: None,
+            paused_since: Cell::new(None),
+            paused_tsc: Cell::new(0),
+            block_overhead: 0,
+            discarded_samples: Cell::new(0),
+            interval: IntervalMark::default(),
+            #[cfg(feature = "std")]
+            color: true,
+            #[cfg(feature = "std")]
+            print_on_drop: false,
+            warmup: 0,
+            omit: Duration::ZERO,
+            omit_mark: RefCell::new(None),
+            omit_pending: Cell::new(false),
+            #[cfg(feature = "std")]
+            started_at: None,
+            #[cfg(feature = "std")]
+            ended_at: None,
+            smoothing_alpha: None,
+            smoothed_throughput: Map::new(),
+            throughput_histograms: None,
+            generation: 0,
+            precision: None,
+            on_block_end: None,
+            ignored: Set::new(),
+            sampling: Map::new(),
+            #[cfg(feature = "std")]
+            separate_reports: false,
+            datagrams: RefCell::new(DatagramStats::new()),
+            panic_over_budget: false,
         }
     }
 
-    #[inline]
-    pub fn start(&mut self) {
-        self.anchors = empty_anchores();
-        self.label_to_index.clear();
-        self.parent_index = 0;
-        self.end_tsc = 0;
-        self.start_tsc = read_cpu_timer();
+    /// A profiler timed by `time_source`. Without the `std` feature nothing is
+    /// printed: take reports with `report` or render them into any
+    /// `fmt::Write` with `write_results`.
+    #[cfg(not(feature = "std"))]
+    pub fn with_time_source(time_source: T) -> Self {
+        Self::with_defaults(time_source)
     }
 
-    pub fn print_results(&mut self) {
-        let cpu_freq = get_cpu_frequency();
-        assert!(cpu_freq > 0);
+    pub fn tsc_is_invariant(&self) -> bool {
+        self.time_source.is_invariant()
+    }
+
+    pub fn set_report_units(&mut self, units: ReportUnits) {
+        self.report_units = units;
+    }
+
+    /// Makes blocks with any of `labels` no-ops, replacing the previous list.
+    /// Anchors they already recorded stay in the report.
+    pub fn set_ignored(&mut self, labels: &[&str]) {
+        self.ignored = labels.iter().map(|&label| label.into()).collect();
+    }
 
-        let end_tsc = if self.end_tsc != 0 {
-            self.end_tsc
+    /// Times only one in `every` hits of `label`, counting the rest without
+    /// reading the timer, for blocks too hot to time on every hit. Each timed
+    /// hit's time is scaled by `every` to extrapolate the skipped ones, while
+    /// min, max and percentiles come from the timed hits alone. Skipped hits
+    /// don't call `on_block_end`. Blocks opened inside a sampled block are still
+    /// timed on every hit, and what they take off its exclusive time is scaled
+    /// the same way. `every` of 0 or 1 times every hit again.
+    pub fn set_sampling(&mut self, label: &str, every: u64) {
+        if every <= 1 {
+            self.sampling.remove(label);
         } else {
-            read_cpu_timer()
-        };
+            self.sampling.insert(label.into(), every);
+        }
+        if let Some(&index) = self.label_to_index.get_mut().get(label) {
+            let anchor = &mut self.anchors.get_mut()[index];
+            anchor.sample_every = every;
+            anchor.sample_phase = 0;
+        }
+    }
 
-        let total_cpu_elapsed = end_tsc - self.start_tsc;
-        let _ = writeln!(self.log_file, "Performance report:");
-        let _ = writeln!(self.log_file, "    CPU frequency: {cpu_freq}hz");
-        let _ = writeln!(
-            self.log_file,
-            "    Total time = {:.4}ms",
-            1000.0 * total_cpu_elapsed as f64 / cpu_freq as f64
-        );
+    /// Calls `callback` with the label, elapsed ticks and bytes of every block as
+    /// it ends, except warmup hits. It runs inside the block's drop, on the hot
+    /// path, so it should be quick.
+    pub fn on_block_end(&mut self, callback: impl Fn(&str, u64, u64) + Send + 'static) {
+        self.on_block_end = Some(Box::new(callback));
+    }
 
-        for anchor in self.anchors.iter().skip(1) {
-            if anchor.tsc_elapsed_exclusive != 0 && anchor.num_hits != 0 {
-                let ms_elapsed = 1000.0 * anchor.tsc_elapsed_exclusive as f64 / cpu_freq as f64;
-                let percentage =
-                    100.0 * (anchor.tsc_elapsed_exclusive as f64 / total_cpu_elapsed as f64);
+    /// Prints times and throughput with `digits` decimals instead of four
+    /// significant digits. Only affects the text report.
+    pub fn set_precision(&mut self, digits: usize) {
+        self.precision = Some(digits);
+    }
 
-                let _ = write!(
-                    self.log_file,
-                    "{}[{}]: {ms_elapsed:.10}ms ({percentage:.2}%",
-                    anchor.label, anchor.num_hits
-                );
+    /// Panics when a block begun with `begin_block_with_budget` runs past its
+    /// budget, instead of warning, to catch regressions in debug builds and
+    /// tests. Release builds have no such option.
+    #[cfg(debug_assertions)]
+    pub fn set_panic_over_budget(&mut self, panic: bool) {
+        self.panic_over_budget = panic;
+    }
 
-                if anchor.tsc_elapsed_exclusive != anchor.tsc_elapsed_inclusive {
-                    let percent_with_children =
-                        100.0 * (anchor.tsc_elapsed_inclusive as f64 / total_cpu_elapsed as f64);
-                    let _ = write!(self.log_file, ", {percent_with_children:.2}% w/children");
-                }
-                let _ = write!(self.log_file, ")");
+    #[cfg(feature = "profiling")]
+    #[inline]
+    fn panics_over_budget(&self) -> bool {
+        cfg!(debug_assertions) && self.panic_over_budget
+    }
+
+    /// Leaves the first `hits` hits of every anchor out of its timings, counting
+    /// them as warmup hits instead, like iperf's `--omit`.
+    pub fn set_warmup(&mut self, hits: u64) {
+        self.warmup = hits;
+    }
 
-                if anchor.bytes_processed != 0 {
-                    let mb = 1024.0 * 1024.0;
-                    let gb = mb * 1024.0;
+    /// Keeps an exponential moving average of each anchor's throughput over the
+    /// interval reports, weighting the newest interval by `alpha` (0 to 1).
+    /// `None` turns it off again.
+    pub fn set_throughput_smoothing(&mut self, alpha: Option<f64>) {
+        self.smoothing_alpha = alpha.map(|alpha| alpha.clamp(0.0, 1.0));
+        self.smoothed_throughput.clear();
+    }
 
-                    let seconds = anchor.tsc_elapsed_inclusive as f64 / cpu_freq as f64;
-                    let bytes_per_second = anchor.bytes_processed as f64 / seconds;
-                    let megabytes = anchor.bytes_processed as f64 / mb;
-                    let gigabytes_per_second = bytes_per_second / gb;
+    /// The smoothed bytes per second of `label` as of the last interval report.
+    pub fn smoothed_throughput(&self, label: &str) -> Option<f64> {
+        self.smoothed_throughput.get(label).copied()
+    }
 
-                    let _ = write!(
-                        self.log_file,
-                        " {megabytes:.3}MBs at {gigabytes_per_second:.2}GB/s"
-                    );
-                }
+    /// Buckets every interval report's throughput per anchor, to tell a steadily
+    /// saturated link from a bursty one. Anchors with no hits in an interval
+    /// after their first count as stalled.
+    pub fn enable_throughput_histograms(&mut self, enabled: bool) {
+        self.throughput_histograms = enabled.then(Map::new);
+    }
+
+    /// The populated buckets of `label`'s throughput histogram, slowest first.
+    pub fn throughput_histogram(&self, label: &str) -> Option<Vec<ThroughputBucket>> {
+        let histogram = self.throughput_histograms.as_ref()?.get(label)?;
+        Some(histogram.buckets())
+    }
+
+    /// Leaves the first `omit` of the run out of the summary reports, like
+    /// iperf's `-O`. Interval reports still cover the omitted window.
+    pub fn set_omit(&mut self, omit: Duration) {
+        self.omit = omit;
+        self.omit_pending
+            .set(!omit.is_zero() && self.omit_mark.get_mut().is_none());
+    }
+
+    pub fn set_sort_by(&mut self, sort_by: SortBy) {
+        self.sort_by = sort_by;
+    }
+
+    pub fn set_top_n(&mut self, top_n: Option<usize>) {
+        self.top_n = top_n;
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Limits the number of distinct labels; blocks for labels past it record nothing.
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+    }
+
+    /// Records per-hit durations for percentiles, mean and standard deviation.
+    pub fn enable_histograms(&mut self) {
+        self.histograms_enabled = true;
+    }
+
+    /// Measures the time source's rate again. Otherwise the profiler keeps the
+    /// rate it read at `start`.
+    pub fn recalibrate(&self) {
+        self.time_source.recalibrate();
+        self.cpu_freq.set(self.time_source.ticks_per_second());
+    }
 
-                let _ = writeln!(self.log_file);
+    // The time source's rate as of the last `start` or `recalibrate`, so block
+    // ends and the omit check don't query the source, which may take a lock.
+    #[inline]
+    fn ticks_per_second(&self) -> u64 {
+        match self.cpu_freq.get() {
+            0 => {
+                let cpu_freq = self.time_source.ticks_per_second();
+                self.cpu_freq.set(cpu_freq);
+                cpu_freq
             }
+            cpu_freq => cpu_freq,
+        }
+    }
+
+    /// Stops attributing time to anchors until `resume`, keeping what was recorded.
+    ///
+    /// Blocks opened while paused record nothing. Blocks that straddle a pause
+    /// still record a hit, but the paused interval is left out of their elapsed
+    /// time, just as it is left out of the total.
+    pub fn pause(&self) {
+        if self.paused_since.get().is_none() {
+            self.paused_since.set(Some(self.time_source.now_ticks()));
+        }
+    }
+
+    pub fn resume(&self) {
+        if let Some(paused_since) = self.paused_since.take() {
+            let paused = self.time_source.now_ticks().saturating_sub(paused_since);
+            self.paused_tsc.set(self.paused_tsc.get() + paused);
         }
     }
 
+    pub fn is_paused(&self) -> bool {
+        self.paused_since.get().is_some()
+    }
+
     #[inline]
-    pub fn begin_block_with_bandwidth(&mut self, label: &str, bytes: u64) -> ProfileBlock {
-        let current_index = self.label_to_index.len() + 1;
-        let index = *self
-            .label_to_index
-            .entry(label.to_string())
-            .or_insert(current_index);
-        ProfileBlock::new(index, label, bytes, self)
+    fn paused_ticks_until(&self, tsc: u64) -> u64 {
+        let ongoing = self
+            .paused_since
+            .get()
+            .map_or(0, |paused_since| tsc.saturating_sub(paused_since));
+        self.paused_tsc.get() + ongoing
     }
 
     #[inline]
-    pub fn begin_block(&mut self, label: &str) -> ProfileBlock {
-        self.begin_block_with_bandwidth(label, 0)
+    pub fn start(&mut self) {
+        self.cpu_freq.set(self.time_source.ticks_per_second());
+        self.reset();
+        let on_block_end = self.on_block_end.take();
+        self.block_overhead = self.measure_block_overhead();
+        self.on_block_end = on_block_end;
+        self.reset();
+    }
+
+    // Takes on what `other` was set up to record, and the rate and block
+    // overhead it measured, so a thread profiler records like the shared one
+    // without measuring the overhead again.
+    #[cfg(feature = "std")]
+    pub(crate) fn copy_settings(&mut self, other: &Profiler<T>) {
+        self.cpu_freq.set(other.cpu_freq.get());
+        self.block_overhead = other.block_overhead;
+        self.report_units = other.report_units;
+        self.histograms_enabled = other.histograms_enabled;
+        self.capacity = other.capacity;
+        self.sort_by = other.sort_by;
+        self.top_n = other.top_n;
+        #[cfg(feature = "std")]
+        {
+            self.color = other.color;
+        }
+        self.precision = other.precision;
+        self.warmup = other.warmup;
+        self.ignored = other.ignored.clone();
+        self.sampling = other.sampling.clone();
+    }
+
+    /// Forgets everything recorded and restarts the run from now, reusing the
+    /// anchor storage. Unlike `start` it doesn't re-measure the block overhead.
+    pub fn reset(&mut self) {
+        let anchors = self.anchors.get_mut();
+        anchors.truncate(ROOT_ANCHOR + 1);
+        anchors[ROOT_ANCHOR] = ProfileAnchor::default();
+        self.label_to_index.get_mut().clear();
+        self.generation += 1;
+        self.capacity_warned.set(false);
+        self.parent_index.set(ROOT_ANCHOR);
+        self.parent_weight.set(1);
+        self.paused_since.set(None);
+        self.paused_tsc.set(0);
+        self.discarded_samples.set(0);
+        *self.datagrams.get_mut() = DatagramStats::new();
+        let start_tsc = self.time_source.now_ticks();
+        self.end_tsc = None;
+        self.start_tsc = Some(start_tsc);
+        #[cfg(feature = "std")]
+        {
+            self.started_at = Some(SystemTime::now());
+            self.ended_at = None;
+        }
+        self.interval.tsc = start_tsc;
+        self.interval.paused_tsc = 0;
+        self.interval.anchors.clear();
+        *self.omit_mark.get_mut() = None;
+        self.smoothed_throughput.clear();
+        if let Some(histograms) = &mut self.throughput_histograms {
+            histograms.clear();
+        }
+        self.omit_pending.set(!self.omit.is_zero());
+    }
+
+    /// Zeroes what `label` recorded so far, so its report covers only the hits
+    /// from now on, e.g. the steady state after a warmup. Other anchors and the
+    /// run's total time are left alone, and warmup hits already taken stay taken.
+    pub fn reset_anchor(&mut self, label: &str) {
+        if let Some(&index) = self.label_to_index.get_mut().get(label) {
+            self.reset_anchor_at(index);
+        }
+    }
+
+    // Zeroes the counters of every anchor like `reset_anchor`, and the run-wide
+    // counts merged along with them, once a thread profiler has published them.
+    #[cfg(feature = "std")]
+    pub(crate) fn reset_counts(&mut self) {
+        for index in ROOT_ANCHOR + 1..self.anchors.get_mut().len() {
+            self.reset_anchor_at(index);
+        }
+        self.discarded_samples.set(0);
+        *self.datagrams.get_mut() = DatagramStats::new();
+    }
+
+    fn reset_anchor_at(&mut self, index: usize) {
+        let anchor = &mut self.anchors.get_mut()[index];
+        *anchor = ProfileAnchor {
+            warmup_hits: anchor.warmup_hits,
+            budget_warned: anchor.budget_warned,
+            parent_index: anchor.parent_index,
+            parents: anchor
+                .parents
+                .iter()
+                .map(|edge| ParentEdge {
+                    hits: 0,
+                    ticks: 0,
+                    ..*edge
+                })
+                .collect(),
+            sample_every: anchor.sample_every,
+            label: anchor.label.clone(),
+            ..ProfileAnchor::default()
+        };
+        let marks = core::iter::once(&mut self.interval).chain(self.omit_mark.get_mut());
+        for mark in marks {
+            if let Some(counts) = mark.anchors.get_mut(index) {
+                *counts = AnchorCounts::default();
+            }
+        }
+    }
+
+    // Takes the mark the summary is measured from once the omitted window is over.
+    #[inline]
+    fn check_omit(&self) {
+        if !self.omit_pending.get() {
+            return;
+        }
+
+        let elapsed = self.total_elapsed_ticks() as u128;
+        let omit_ticks = self.omit.as_nanos() * self.ticks_per_second() as u128 / 1_000_000_000;
+        if elapsed >= omit_ticks {
+            self.omit_pending.set(false);
+            *self.omit_mark.borrow_mut() = Some(self.interval_mark());
+        }
+    }
+
+    // The average exclusive time of an empty block, i.e. what the profiler
+    // itself adds to every hit. Runs on the live tables, so `start` resets them after.
+    fn measure_block_overhead(&self) -> u64 {
+        if self.capacity == 0 {
+            return 0;
+        }
+
+        for _ in 0..OVERHEAD_SAMPLES {
+            let _block = self.begin_block(OVERHEAD_LABEL);
+        }
+
+        let index = self.label_to_index.borrow().get(OVERHEAD_LABEL).copied();
+        index.map_or(0, |index| {
+            let anchor = &self.anchors.borrow()[index];
+            anchor
+                .tsc_elapsed_exclusive
+                .checked_div(anchor.num_hits)
+                .unwrap_or(0)
+        })
+    }
+
+    /// Ticks subtracted from every hit's exclusive time, measured by `start`.
+    pub fn block_overhead_ticks(&self) -> u64 {
+        self.block_overhead
+    }
+
+    /// Blocks dropped because the timer read went backwards between their start and end.
+    pub fn discarded_samples(&self) -> u64 {
+        self.discarded_samples.get()
+    }
+
+    // Every hit pays the overhead once, in both its exclusive and inclusive time.
+    #[inline]
+    fn without_overhead(&self, ticks: u64, hits: u64) -> u64 {
+        ticks.saturating_sub(hits.saturating_mul(self.block_overhead))
+    }
+
+    /// Counts a datagram of a UDP stream for the loss and jitter in the report,
+    /// see [`DatagramStats::record`].
+    pub fn record_datagram(&self, sequence: u64, sent: Duration, arrived: Duration) {
+        self.datagrams.borrow_mut().record(sequence, sent, arrived);
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.start_tsc.is_some() && self.end_tsc.is_none()
+    }
+
+    /// Ticks from `start` to the end of the run, or to now while it is still running.
+    pub fn total_elapsed_ticks(&self) -> u64 {
+        let Some(start_tsc) = self.start_tsc else {
+            return 0;
+        };
+
+        let end_tsc = self.end_or_now_tsc();
+        end_tsc
+            .saturating_sub(start_tsc)
+            .saturating_sub(self.paused_ticks_until(end_tsc))
     }
 
     #[inline]
-    pub fn end_and_print_results(&mut self) {
-        self.end_tsc = read_cpu_timer();
-        self.print_results();
+    fn end_or_now_tsc(&self) -> u64 {
+        self.end_tsc.unwrap_or_else(|| self.time_source.now_ticks())
+    }
+
+    /// Converts `ticks` of this profiler's time source to nanoseconds, e.g. for
+    /// comparing tick counts taken on machines with different counter rates.
+    pub fn to_nanos(&self, ticks: u64) -> u64 {
+        ticks_to_nanos(ticks, self.ticks_per_second())
+    }
+
+    pub fn total_elapsed(&self) -> Duration {
+        ticks_to_duration(self.total_elapsed_ticks(), self.ticks_per_second())
+    }
+
+    pub fn report(&self) -> ProfileReport {
+        let mut report = self.raw_report();
+        if let Some(n) = self.top_n {
+            report.keep_top(n);
+        }
+        report.sort_by(self.sort_by);
+        report
+    }
+
+    pub fn snapshot(&self) -> ProfileSnapshot {
+        ProfileSnapshot::from(&self.raw_report())
+    }
+
+    /// Checks the recorded counters for accounting bugs, such as an exclusive time
+    /// that wrapped below zero. Call it with no blocks open. Like `debug_assert!`
+    /// it only exists in debug builds.
+    #[cfg(debug_assertions)]
+    pub fn validate(&self) -> Result<(), Vec<Violation>> {
+        let total_ticks = self.total_elapsed_ticks();
+        let anchors = self.anchors.borrow();
+        let mut violations = Vec::new();
+
+        for anchor in anchors.iter().skip(1) {
+            let label = &anchor.label;
+            let (exclusive_ticks, inclusive_ticks) =
+                (anchor.tsc_elapsed_exclusive, anchor.tsc_elapsed_inclusive);
+
+            if exclusive_ticks > total_ticks {
+                violations.push(Violation::ExclusiveExceedsTotal {
+                    label: label.clone(),
+                    exclusive_ticks,
+                });
+            }
+            if inclusive_ticks < exclusive_ticks {
+                violations.push(Violation::InclusiveBelowExclusive {
+                    label: label.clone(),
+                    exclusive_ticks,
+                    inclusive_ticks,
+                });
+            }
+            let parents = anchor.parents.iter().map(|edge| edge.index);
+            for parent_index in core::iter::once(anchor.parent_index).chain(parents) {
+                if parent_index >= anchors.len() {
+                    violations.push(Violation::ParentOutOfRange {
+                        label: label.clone(),
+                        parent_index,
+                    });
+                }
+            }
+        }
+
+        let exclusive_ticks: u128 = anchors
+            .iter()
+            .skip(1)
+            .map(|anchor| anchor.tsc_elapsed_exclusive as u128)
+            .sum();
+        if exclusive_ticks > total_ticks as u128 {
+            violations.push(Violation::ExclusiveSumExceedsTotal {
+                exclusive_ticks,
+                total_ticks,
+            });
+        }
+
+        if violations.is_empty() {
+            Ok(())
+        } else {
+            Err(violations)
+        }
+    }
+
+    /// See [`ProfileReport::summary_line`].
+    pub fn summary_line(&self) -> String {
+        self.raw_report().summary_line()
+    }
+
+    pub fn anchor(&self, label: &str) -> Option<AnchorStats> {
+        let index = *self.label_to_index.borrow().get(label)?;
+        self.anchor_stats(index, self.ticks_per_second())
+    }
+
+    // Anchors with at least one hit, in registration order. Each step only
+    // borrows the anchor table briefly, so blocks may be recorded mid-iteration.
+    pub fn iter_anchors(&self) -> impl Iterator<Item = AnchorStats> + '_ {
+        let cpu_freq = self.ticks_per_second();
+        let len = self.anchors.borrow().len();
+        (ROOT_ANCHOR + 1..len).filter_map(move |index| self.anchor_stats(index, cpu_freq))
+    }
+
+    fn anchor_stats(&self, index: usize, cpu_freq: u64) -> Option<AnchorStats> {
+        let anchors = self.anchors.borrow();
+        let anchor = anchors.get(index)?;
+        if anchor.num_hits == 0 {
+            return None;
+        }
+
+        let ticks_to_ms = |ticks: u64| 1000.0 * ticks as f64 / cpu_freq as f64;
+        let exclusive_ticks = self.without_overhead(anchor.tsc_elapsed_exclusive, anchor.num_hits);
+        let inclusive_ticks = self.without_overhead(anchor.tsc_elapsed_inclusive, anchor.num_hits);
+        Some(AnchorStats {
+            label: anchor.label.clone(),
+            hits: anchor.num_hits,
+            exclusive_ticks,
+            inclusive_ticks,
+            exclusive_ms: ticks_to_ms(exclusive_ticks),
+            inclusive_ms: ticks_to_ms(inclusive_ticks),
+            bytes: anchor.bytes_processed,
+            tx_bytes: anchor.tx_bytes,
+            rx_bytes: anchor.rx_bytes,
+            over_budget: anchor.over_budget,
+            warmup_hits: anchor.warmup_hits,
+        })
+    }
+
+    // All recorded anchors in registration order, before sorting or top-N folding,
+    // leaving out the omitted window once it has passed.
+    fn raw_report(&self) -> ProfileReport {
+        self.check_omit();
+        self.report_since(self.omit_mark.borrow().as_ref())
+    }
+
+    // Like `raw_report`, but with counters relative to `mark` when given. Min/max,
+    // last and percentiles aren't tracked per interval and always cover the whole run.
+    fn report_since(&self, mark: Option<&IntervalMark>) -> ProfileReport {
+        let cpu_freq = self.ticks_per_second();
+        assert!(cpu_freq > 0);
+
+        let total_cpu_elapsed = match mark {
+            Some(mark) => {
+                let end_tsc = self.end_or_now_tsc();
+                let paused = self.paused_ticks_until(end_tsc) - mark.paused_tsc;
+                end_tsc.saturating_sub(mark.tsc).saturating_sub(paused)
+            }
+            None => self.total_elapsed_ticks(),
+        };
+        let ticks_to_ms = |ticks: u64| 1000.0 * ticks as f64 / cpu_freq as f64;
+        let ticks_to_ms_f64 = |ticks: f64| 1000.0 * ticks / cpu_freq as f64;
+        let total_seconds = total_cpu_elapsed as f64 / cpu_freq as f64;
+
+        let all_anchors = self.anchors.borrow();
+        let all_counts: Vec<AnchorCounts> = all_anchors
+            .iter()
+            .enumerate()
+            .map(|(index, anchor)| {
+                let base = mark
+                    .and_then(|mark| mark.anchors.get(index))
+                    .copied()
+                    .unwrap_or_default();
+                base.delta(anchor)
+            })
+            .collect();
+        let inclusive_of =
+            |counts: &AnchorCounts| self.without_overhead(counts.inclusive, counts.hits);
+
+        let anchors = all_anchors
+            .iter()
+            .zip(&all_counts)
+            .skip(1)
+            .filter(|(_, counts)| counts.exclusive != 0 && counts.hits != 0)
+            .map(|(anchor, counts)| {
+                let exclusive_ticks = self.without_overhead(counts.exclusive, counts.hits);
+                let inclusive_ticks = inclusive_of(counts);
+                // Only the hits under that parent count, so a label it shares
+                // with other callers can't take more than the parent's whole time.
+                let percent_of_parent = anchor
+                    .parents
+                    .iter()
+                    .find(|edge| edge.index == anchor.parent_index && edge.index != ROOT_ANCHOR)
+                    .map(|edge| {
+                        let parent = &all_anchors[edge.index];
+                        let parent_ticks =
+                            self.without_overhead(parent.tsc_elapsed_inclusive, parent.num_hits);
+                        (self.without_overhead(edge.ticks, edge.hits), parent_ticks)
+                    })
+                    .filter(|&(_, parent_ticks)| parent_ticks != 0)
+                    .map(|(ticks, parent_ticks)| 100.0 * ticks as f64 / parent_ticks as f64);
+                let seconds = inclusive_ticks as f64 / cpu_freq as f64;
+                // Edges `reset_anchor` zeroed stay around but no longer count.
+                let called_from = || anchor.parents.iter().filter(|edge| edge.hits != 0);
+                let histogram = anchor.histogram.as_ref();
+                let percentile_ms = |quantile: f64| {
+                    histogram
+                        .and_then(|histogram| histogram.percentile(quantile))
+                        .map(ticks_to_ms)
+                };
+                AnchorReport {
+                    label: anchor.label.to_string(),
+                    parent: (anchor.parent_index != ROOT_ANCHOR)
+                        .then(|| all_anchors[anchor.parent_index].label.to_string()),
+                    callers: called_from()
+                        .filter(|edge| edge.index != ROOT_ANCHOR)
+                        .map(|edge| all_anchors[edge.index].label.to_string())
+                        .collect(),
+                    call_sites: called_from().count(),
+                    hits: counts.hits,
+                    exclusive_ticks,
+                    inclusive_ticks,
+                    exclusive_ms: ticks_to_ms(exclusive_ticks),
+                    inclusive_ms: ticks_to_ms(inclusive_ticks),
+                    min_ms: ticks_to_ms(anchor.min_tsc.unwrap_or(0)),
+                    max_ms: ticks_to_ms(anchor.max_tsc),
+                    last_ms: ticks_to_ms(anchor.last_tsc),
+                    p50_ms: percentile_ms(0.50),
+                    p90_ms: percentile_ms(0.90),
+                    p99_ms: percentile_ms(0.99),
+                    mean_ms: histogram.and_then(Histogram::mean).map(ticks_to_ms_f64),
+                    stddev_ms: histogram.and_then(Histogram::stddev).map(ticks_to_ms_f64),
+                    percent: 100.0 * ratio(exclusive_ticks as f64, total_cpu_elapsed as f64),
+                    percent_with_children: 100.0
+                        * ratio(inclusive_ticks as f64, total_cpu_elapsed as f64),
+                    percent_of_parent,
+                    bytes: counts.bytes,
+                    tx_bytes: counts.tx_bytes,
+                    rx_bytes: counts.rx_bytes,
+                    over_budget: anchor.over_budget,
+                    warmup_hits: anchor.warmup_hits,
+                    bytes_per_second: ratio(counts.bytes as f64, seconds),
+                    bytes_per_hit: ratio(counts.bytes as f64, counts.hits as f64),
+                    cycles_per_byte: (counts.bytes != 0)
+                        .then(|| inclusive_ticks as f64 / counts.bytes as f64),
+                    hits_per_second: ratio(counts.hits as f64, total_seconds),
+                }
+            })
+            .collect::<Vec<_>>();
+
+        let timed_ticks = anchors.iter().fold(0u64, |sum, anchor| {
+            sum.saturating_add(anchor.exclusive_ticks)
+        });
+        let root_exclusive_ticks = total_cpu_elapsed.saturating_sub(timed_ticks);
+        let root = AnchorReport {
+            hits: 1,
+            exclusive_ticks: root_exclusive_ticks,
+            inclusive_ticks: total_cpu_elapsed,
+            exclusive_ms: ticks_to_ms(root_exclusive_ticks),
+            inclusive_ms: ticks_to_ms(total_cpu_elapsed),
+            percent: 100.0 * ratio(root_exclusive_ticks as f64, total_cpu_elapsed as f64),
+            percent_with_children: 100.0
+                * ratio(total_cpu_elapsed as f64, total_cpu_elapsed as f64),
+            hits_per_second: ratio(1.0, total_seconds),
+            ..AnchorReport::empty(ROOT_LABEL)
+        };
+
+        ProfileReport {
+            cpu_freq,
+            total_ticks: total_cpu_elapsed,
+            total_ms: ticks_to_ms(total_cpu_elapsed),
+            anchors,
+            other: None,
+            root,
+            discarded_samples: self.discarded_samples.get(),
+            #[cfg(feature = "std")]
+            started_at: self.started_at,
+            #[cfg(feature = "std")]
+            ended_at: self
+                .started_at
+                .map(|_| self.ended_at.unwrap_or_else(SystemTime::now)),
+            datagrams: {
+                let datagrams = self.datagrams.borrow().report();
+                (datagrams.total != 0).then_some(datagrams)
+            },
+        }
+    }
+
+    fn interval_mark(&self) -> IntervalMark {
+        let tsc = self.end_or_now_tsc();
+        IntervalMark {
+            tsc,
+            paused_tsc: self.paused_ticks_until(tsc),
+            anchors: self.anchors.borrow().iter().map(AnchorCounts::of).collect(),
+        }
+    }
+
+    /// Reports only what was recorded since the previous call, or since `start`.
+    pub fn report_interval(&mut self) -> ProfileReport {
+        self.check_omit();
+        let mut report = self.report_since(Some(&self.interval));
+        let mut mark = self.interval_mark();
+        // A timer that went backwards would make the next interval end before it
+        // starts; count it like a discarded block and measure on from the old mark.
+        if mark.tsc < self.interval.tsc {
+            self.discarded_samples.set(self.discarded_samples.get() + 1);
+            report.discarded_samples += 1;
+            mark.tsc = self.interval.tsc;
+        }
+        self.interval = mark;
+        if let Some(alpha) = self.smoothing_alpha {
+            self.smooth_throughput(&report, alpha);
+        }
+        if let Some(histograms) = &mut self.throughput_histograms {
+            record_throughput(histograms, &report);
+        }
+        if let Some(n) = self.top_n {
+            report.keep_top(n);
+        }
+        report.sort_by(self.sort_by);
+        report
+    }
+
+    fn smooth_throughput(&mut self, report: &ProfileReport, alpha: f64) {
+        let seconds = report.total_ms / 1000.0;
+        // Labels that were quiet this interval decay towards zero.
+        for average in self.smoothed_throughput.values_mut() {
+            *average *= 1.0 - alpha;
+        }
+        for anchor in &report.anchors {
+            let throughput = ratio(anchor.bytes as f64, seconds);
+            match self.smoothed_throughput.get_mut(&anchor.label) {
+                Some(average) => *average += alpha * throughput,
+                None => {
+                    self.smoothed_throughput
+                        .insert(anchor.label.clone(), throughput);
+                }
+            }
+        }
+    }
+
+    /// Renders the report `print_results` prints into `w`, e.g. a `String`, or
+    /// a serial port without std.
+    pub fn write_results<W: fmt::Write>(&self, w: &mut W) -> fmt::Result {
+        if self.start_tsc.is_none() {
+            return writeln!(w, "{NOT_STARTED_MESSAGE}");
+        }
+
+        self.report()
+            .format_text_colored(w, self.report_units, false, self.precision)
+    }
+
+    #[inline]
+    fn anchor_index(&self, label: &str) -> Option<usize> {
+        if let Some(&index) = self.label_to_index.borrow().get(label) {
+            return Some(index);
+        }
+
+        let mut label_to_index = self.label_to_index.borrow_mut();
+        if label_to_index.len() >= self.capacity {
+            if !self.capacity_warned.replace(true) {
+                #[cfg(feature = "std")]
+                eprintln!(
+                    "warning: profiler capacity of {} labels reached, not recording \"{label}\" and any further new labels",
+                    self.capacity
+                );
+            }
+            return None;
+        }
+
+        let index = label_to_index.len() + 1;
+        let label: Arc<str> = Arc::from(label);
+        label_to_index.insert(label.clone(), index);
+        let sample_every = self.sampling.get(&*label).copied().unwrap_or_default();
+        self.anchors.borrow_mut().push(ProfileAnchor {
+            sample_every,
+            label,
+            ..Default::default()
+        });
+        Some(index)
+    }
+
+    /// Adds `other`'s anchors into this profiler, summing anchors with the same
+    /// label and adding the rest as new labels. Both should share a tick rate,
+    /// e.g. profilers of the same process.
+    pub fn merge(&mut self, other: &Profiler<T>) {
+        self.merge_anchors(other);
+    }
+
+    fn merge_anchors(&self, other: &Profiler<T>) {
+        let discarded = self.discarded_samples.get() + other.discarded_samples.get();
+        self.discarded_samples.set(discarded);
+        self.datagrams.borrow_mut().merge(&other.datagrams.borrow());
+
+        let other_anchors = other.anchors.borrow();
+        let index_map: Vec<(usize, usize)> = other_anchors
+            .iter()
+            .enumerate()
+            .skip(1)
+            .filter(|(_, anchor)| anchor.num_hits != 0)
+            .filter_map(|(other_index, anchor)| {
+                Some((other_index, self.anchor_index(&anchor.label)?))
+            })
+            .collect();
+        let parent_map: Map<usize, usize> = index_map.iter().copied().collect();
+
+        let mut anchors = self.anchors.borrow_mut();
+        for &(other_index, index) in &index_map {
+            anchors[index].merge(&other_anchors[other_index], &parent_map);
+        }
+    }
+
+    #[inline]
+    pub fn begin_block_with_bandwidth(&self, label: &str, bytes: u64) -> ProfileBlock<'_, T> {
+        if !cfg!(feature = "profiling") || self.is_paused() {
+            return ProfileBlock::noop(self);
+        }
+        self.check_omit();
+        if !self.ignored.is_empty() && self.ignored.contains(label) {
+            return ProfileBlock::noop(self);
+        }
+
+        match self.anchor_index(label) {
+            Some(index) => ProfileBlock::new(index, bytes, self),
+            None => ProfileBlock::noop(self),
+        }
+    }
+
+    /// Looks up or creates the anchor for `label` once, for `begin_block_by_id`.
+    /// `None` when the label is ignored or the profiler is out of capacity for
+    /// new labels.
+    pub fn register_anchor(&self, label: &str) -> Option<AnchorId> {
+        if self.ignored.contains(label) {
+            return None;
+        }
+        Some(AnchorId {
+            index: self.anchor_index(label)?,
+            generation: self.generation,
+        })
+    }
+
+    /// Like `begin_block`, without looking up the label. An id from before the
+    /// last `start` or `reset` records nothing.
+    #[inline]
+    pub fn begin_block_by_id(&self, id: AnchorId) -> ProfileBlock<'_, T> {
+        self.begin_block_by_id_with_bandwidth(id, 0)
+    }
+
+    #[inline]
+    pub fn begin_block_by_id_with_bandwidth(
+        &self,
+        id: AnchorId,
+        bytes: u64,
+    ) -> ProfileBlock<'_, T> {
+        if !cfg!(feature = "profiling") || self.is_paused() || id.generation != self.generation {
+            return ProfileBlock::noop(self);
+        }
+        self.check_omit();
+
+        ProfileBlock::new(id.index, bytes, self)
+    }
+
+    /// A block whose `bytes` count towards the TX or RX total as well as its throughput.
+    pub fn begin_block_with_direction(
+        &self,
+        label: &str,
+        bytes: u64,
+        direction: Direction,
+    ) -> ProfileBlock<'_, T> {
+        self.begin_block_with_bandwidth(label, bytes)
+            .with_direction(direction)
+    }
+
+    #[inline]
+    pub fn begin_tx_block(&self, label: &str, bytes: u64) -> ProfileBlock<'_, T> {
+        self.begin_block_with_direction(label, bytes, Direction::Tx)
+    }
+
+    #[inline]
+    pub fn begin_rx_block(&self, label: &str, bytes: u64) -> ProfileBlock<'_, T> {
+        self.begin_block_with_direction(label, bytes, Direction::Rx)
+    }
+
+    /// A block that counts the hit as over budget when it runs longer than
+    /// `budget`. The first overrun of each label also prints a warning.
+    pub fn begin_block_with_budget(&self, label: &str, budget: Duration) -> ProfileBlock<'_, T> {
+        self.begin_block(label).with_budget(budget)
+    }
+
+    #[inline]
+    pub fn begin_block(&self, label: &str) -> ProfileBlock<'_, T> {
+        self.begin_block_with_bandwidth(label, 0)
+    }
+
+    /// Ends `block` right away, the same as dropping it. Useful where the end of a
+    /// block doesn't line up with a scope, e.g. across an FFI boundary. Taking the
+    /// block by value means it can't be ended twice.
+    #[inline]
+    pub fn end_block(&self, _block: ProfileBlock<'_, T>) {}
+
+    /// Times `f` as a block labelled `label`.
+    ///
+    /// `f` and its result pass through [`core::hint::black_box`] inside the
+    /// block, so the optimizer can't hoist pure work out of it or drop work
+    /// whose result goes unused. This is best effort: values `f` computes only
+    /// from constants may still be folded at compile time.
+    #[inline]
+    pub fn profile<R>(&self, label: &str, f: impl FnOnce() -> R) -> R {
+        self.profile_with_bandwidth(label, 0, f)
+    }
+
+    #[inline]
+    pub fn profile_with_bandwidth<R>(&self, label: &str, bytes: u64, f: impl FnOnce() -> R) -> R {
+        let _block = self.begin_block_with_bandwidth(label, bytes);
+        black_box(black_box(f)())
+    }
+
+    /// Like `profile`, also returning how many ticks `f` took.
+    #[inline]
+    pub fn profile_timed<R>(&self, label: &str, f: impl FnOnce() -> R) -> (R, u64) {
+        let _block = self.begin_block(label);
+        let start_tsc = self.time_source.now_ticks();
+        let result = black_box(black_box(f)());
+        let elapsed = self.time_source.now_ticks().saturating_sub(start_tsc);
+        (result, elapsed)
+    }
+
+    /// Ends the run without printing; later reports all use this end time.
+    pub fn stop(&mut self) {
+        if self.is_running() {
+            self.end_tsc = Some(self.time_source.now_ticks());
+            #[cfg(feature = "std")]
+            {
+                self.ended_at = Some(SystemTime::now());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::timer::MockTimeSource;
+    #[cfg(feature = "std")]
+    use std::io::Write;
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn profilers_start_with_empty_anchors() {
+        // Meant to run under Miri too, which rejects reading uninitialized anchors.
+        for _ in 0..4 {
+            let mut profiler = Profiler::new();
+            profiler.start();
+            assert!(profiler
+                .anchors
+                .get_mut()
+                .iter()
+                .all(|anchor| anchor.num_hits == 0 && anchor.label.is_empty()));
+        }
+    }
+
+    // The core on its own: the caller's time source in, a `fmt::Write` out,
+    // and no stdout or file involved. Also runs without std.
+    #[cfg(feature = "profiling")]
+    #[test]
+    fn results_render_into_any_fmt_sink() {
+        let clock = MockTimeSource::new(1000);
+        let mut profiler = Profiler::with_time_source(clock.clone());
+        let mut text = String::new();
+        profiler.write_results(&mut text).unwrap();
+        assert_eq!(text, format!("{NOT_STARTED_MESSAGE}\n"));
+
+        profiler.start();
+        profiler.profile("send", || clock.advance(20));
+        clock.advance(20);
+        profiler.stop();
+
+        let mut text = String::new();
+        profiler.write_results(&mut text).unwrap();
+        assert!(text.starts_with("Performance report:\n"), "{text}");
+        assert!(
+            text.contains("send[1]: 20.00ms (50.00%, - of parent) 25hits/s\n"),
+            "{text}"
+        );
+    }
+
+    #[cfg(feature = "profiling")]
+    #[test]
+    fn nested_blocks_share_the_profiler() {
+        let (clock, profiler) = started(1000);
+
+        let outer = profiler.begin_block("outer");
+        clock.advance(10);
+        let inner = profiler.begin_block("inner");
+        assert_eq!(profiler.parent_index.get(), inner.open.anchor_index);
+        assert_eq!(inner.open.parent_index, outer.open.anchor_index);
+        assert_eq!(outer.open.parent_index, ROOT_ANCHOR);
+
+        clock.advance(30);
+        drop(inner);
+        assert_eq!(profiler.parent_index.get(), outer.open.anchor_index);
+        clock.advance(5);
+        drop(outer);
+        assert_eq!(profiler.parent_index.get(), ROOT_ANCHOR);
+
+        let ticks = |label: &str| {
+            let anchor = profiler.anchor(label).unwrap();
+            (anchor.hits, anchor.exclusive_ticks, anchor.inclusive_ticks)
+        };
+        assert_eq!(ticks("outer"), (1, 15, 45));
+        assert_eq!(ticks("inner"), (1, 30, 30));
+        assert_eq!(profiler.label_to_index.borrow().len(), 2);
+    }
+
+    // A profiler on a mock clock of `ticks_per_second`, started, and the clock
+    // that drives it.
+    pub(crate) fn started(ticks_per_second: u64) -> (MockTimeSource, Profiler<MockTimeSource>) {
+        let clock = MockTimeSource::new(ticks_per_second);
+        let mut profiler = Profiler::with_time_source(clock.clone());
+        profiler.start();
+        (clock, profiler)
+    }
+
+    // Registers `label` with the given totals, as if its blocks had run.
+    pub(crate) fn record(
+        profiler: &Profiler<MockTimeSource>,
+        label: &str,
+        exclusive: u64,
+        inclusive: u64,
+        hits: u64,
+    ) -> usize {
+        let index = profiler.anchor_index(label).unwrap();
+        let anchor = &mut profiler.anchors.borrow_mut()[index];
+        anchor.tsc_elapsed_exclusive = exclusive;
+        anchor.tsc_elapsed_inclusive = inclusive;
+        anchor.num_hits = hits;
+        index
+    }
+
+    // A sink whose contents tests can read back after the profiler wrote to it.
+    #[cfg(feature = "std")]
+    #[derive(Clone, Default)]
+    pub(crate) struct Captured(Arc<std::sync::Mutex<Vec<u8>>>);
+
+    #[cfg(feature = "std")]
+    impl Captured {
+        pub(crate) fn text(&self) -> String {
+            String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+        }
+    }
+
+    #[cfg(feature = "std")]
+    impl Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // Ends the run and returns what `print_results` wrote.
+    #[cfg(feature = "std")]
+    pub(crate) fn printed(profiler: &mut Profiler<MockTimeSource>) -> String {
+        printed_with(profiler, Profiler::end_and_print_results)
+    }
+
+    // Returns what `print` wrote to the profiler's output.
+    #[cfg(feature = "std")]
+    pub(crate) fn printed_with(
+        profiler: &mut Profiler<MockTimeSource>,
+        print: impl FnOnce(&mut Profiler<MockTimeSource>),
+    ) -> String {
+        let captured = Captured::default();
+        profiler.set_output(captured.clone());
+        print(profiler);
+        captured.text()
+    }
+
+    #[cfg(feature = "profiling")]
+    #[test]
+    fn min_max_and_last_hit_durations() {
+        let (clock, profiler) = started(1000);
+        for ticks in [40, 10, 70, 20] {
+            let _block = profiler.begin_block("hit");
+            clock.advance(ticks);
+        }
+
+        let report = profiler.report();
+        let hit = &report.anchors[0];
+        assert_eq!(hit.hits, 4);
+        assert_eq!((hit.min_ms, hit.max_ms, hit.last_ms), (10.0, 70.0, 20.0));
+        assert_eq!(hit.exclusive_ms / hit.hits as f64, 35.0);
+    }
+
+    #[cfg(feature = "profiling")]
+    #[test]
+    fn profile_returns_the_closure_value() {
+        let profiler = Profiler::with_time_source(MockTimeSource::new(1000));
+        let value = profiler.profile("compute", || 6 * 7);
+        assert_eq!(value, 42);
+        let bytes = profiler.profile_with_bandwidth("copy", 512, || "done");
+        assert_eq!(bytes, "done");
+
+        let label_to_index = profiler.label_to_index.borrow();
+        let anchors = profiler.anchors.borrow();
+        assert_eq!(anchors[label_to_index["compute"]].num_hits, 1);
+        assert_eq!(anchors[label_to_index["copy"]].num_hits, 1);
+        assert_eq!(anchors[label_to_index["copy"]].bytes_processed, 512);
+        assert_eq!(profiler.parent_index.get(), 0);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn many_profilers_fit_on_a_small_stack() {
+        std::thread::Builder::new()
+            .stack_size(128 * 1024)
+            .spawn(|| {
+                for _ in 0..1000 {
+                    let (_, profiler) = started(1000);
+                    let _block = profiler.begin_block("block");
+                }
+            })
+            .unwrap()
+            .join()
+            .unwrap();
+    }
+
+    #[cfg(feature = "profiling")]
+    #[test]
+    fn labels_past_the_capacity_get_no_op_blocks() {
+        let (_, mut profiler) = started(1000);
+        profiler.set_capacity(2);
+
+        assert!(profiler.begin_block("first").is_recording());
+        assert!(profiler.begin_block("second").is_recording());
+        let overflow = profiler.begin_block("third");
+        assert!(!overflow.is_recording());
+        drop(overflow);
+        assert!(profiler.begin_block("first").is_recording());
+        assert_eq!(profiler.parent_index.get(), ROOT_ANCHOR);
+        assert_eq!(profiler.label_to_index.borrow().len(), 2);
+    }
+
+    #[cfg(feature = "profiling")]
+    #[test]
+    fn large_capacities_hold_every_label() {
+        let (_, mut profiler) = started(1000);
+        profiler.set_capacity(20_000);
+
+        let labels: Vec<String> = (0..20_000).map(|i| format!("label {i}")).collect();
+        for label in &labels {
+            assert!(profiler.begin_block(label).is_recording());
+        }
+        assert_eq!(profiler.anchors.borrow().len(), 20_001);
+    }
+
+    #[cfg(feature = "profiling")]
+    #[test]
+    fn five_thousand_labels_do_not_panic() {
+        let (_, mut profiler) = started(1000);
+
+        for connection in 0..5000 {
+            drop(profiler.begin_block(&format!("connection {connection}")));
+        }
+        assert!(profiler.capacity_warned.get());
+        assert_eq!(profiler.label_to_index.borrow().len(), profiler.capacity());
+
+        profiler.start();
+        assert!(!profiler.capacity_warned.get());
+    }
+
+    #[cfg(feature = "profiling")]
+    fn recurse(profiler: &Profiler<MockTimeSource>, clock: &MockTimeSource, depth: u32) {
+        let _block = profiler.begin_block("recurse");
+        clock.advance(10);
+        if depth > 0 {
+            recurse(profiler, clock, depth - 1);
+        }
+    }
+
+    #[cfg(feature = "profiling")]
+    #[test]
+    fn recursion_keeps_exclusive_time_exact() {
+        let (clock, profiler) = started(1000);
+        {
+            let _main = profiler.begin_block("main");
+            clock.advance(5);
+            recurse(&profiler, &clock, 3);
+        }
+
+        let report = profiler.report();
+        assert_eq!(report.total_ticks, 45);
+        let anchor = |label: &str| {
+            report
+                .anchors
+                .iter()
+                .find(|anchor| anchor.label == label)
+                .unwrap()
+        };
+        let main = anchor("main");
+        assert_eq!((main.exclusive_ticks, main.inclusive_ticks), (5, 45));
+        let recursive = anchor("recurse");
+        assert_eq!(recursive.hits, 4);
+        assert_eq!(
+            (recursive.exclusive_ticks, recursive.inclusive_ticks),
+            (40, 40)
+        );
+        assert!(report.anchors.iter().all(|anchor| anchor.percent <= 100.0));
+    }
+
+    #[cfg(feature = "profiling")]
+    fn fibonacci(profiler: &Profiler<MockTimeSource>, clock: &MockTimeSource, n: u64) -> u64 {
+        let _block = profiler.begin_block("fibonacci");
+        clock.advance(1);
+        if n < 2 {
+            n
+        } else {
+            fibonacci(profiler, clock, n - 1) + fibonacci(profiler, clock, n - 2)
+        }
+    }
+
+    #[cfg(feature = "profiling")]
+    #[test]
+    fn recursive_fibonacci_report_is_sane() {
+        let (clock, profiler) = started(1000);
+        {
+            let _main = profiler.begin_block("main");
+            clock.advance(100);
+            assert_eq!(fibonacci(&profiler, &clock, 10), 55);
+        }
+
+        let report = profiler.report();
+        let fibonacci = report
+            .anchors
+            .iter()
+            .find(|anchor| anchor.label == "fibonacci")
+            .unwrap();
+        assert_eq!(fibonacci.hits, 177);
+        assert_eq!(fibonacci.parent.as_deref(), Some("main"));
+        assert_eq!(
+            (fibonacci.exclusive_ticks, fibonacci.inclusive_ticks),
+            (177, 177)
+        );
+        for anchor in &report.anchors {
+            assert!(anchor.inclusive_ticks >= anchor.exclusive_ticks);
+            assert!(anchor.percent <= 100.0 && anchor.percent_with_children <= 100.0);
+        }
+    }
+
+    #[cfg(feature = "profiling")]
+    #[test]
+    fn paused_time_is_not_attributed() {
+        let (clock, profiler) = started(1000);
+        {
+            let _transfer = profiler.begin_block("transfer");
+            clock.advance(100);
+            profiler.pause();
+            assert!(!profiler.begin_block("handshake").is_recording());
+            clock.advance(5000);
+            profiler.resume();
+            clock.advance(50);
+        }
+        clock.advance(10);
+
+        let report = profiler.report();
+        assert_eq!(report.total_ticks, 160);
+        assert_eq!(report.anchors.len(), 1);
+        let transfer = &report.anchors[0];
+        assert_eq!((transfer.hits, transfer.exclusive_ticks), (1, 150));
+        assert!(!profiler.is_paused());
+    }
+
+    #[test]
+    fn resume_survives_a_timer_that_went_backwards() {
+        let (clock, profiler) = started(1000);
+        clock.advance(100);
+        profiler.pause();
+        clock.set(clock.now_ticks() - 30);
+        profiler.resume();
+
+        assert!(!profiler.is_paused());
+        assert_eq!(profiler.paused_tsc.get(), 0);
+    }
+
+    #[cfg(feature = "profiling")]
+    #[test]
+    fn anchor_stats_for_a_label() {
+        let (clock, profiler) = started(1000);
+        profiler.profile_with_bandwidth("serialize", 256, || clock.advance(3));
+        assert_eq!(profiler.anchor("missing"), None);
+
+        let stats = profiler.anchor("serialize").unwrap();
+        assert_eq!(&*stats.label, "serialize");
+        assert_eq!(stats.hits, 1);
+        assert_eq!((stats.exclusive_ticks, stats.inclusive_ticks), (3, 3));
+        assert!(stats.exclusive_ms > 0.0 && stats.exclusive_ms < 5.0);
+        assert_eq!(stats.bytes, 256);
+    }
+
+    #[cfg(feature = "profiling")]
+    #[test]
+    fn iter_anchors_skips_root_and_unused_anchors() {
+        let (clock, profiler) = started(1000);
+        profiler.profile("connect", || clock.advance(5));
+        for _ in 0..3 {
+            profiler.profile("send", || clock.advance(2));
+        }
+        profiler.anchor_index("never hit");
+        profiler.profile("close", || clock.advance(1));
+
+        let stats: Vec<(String, u64, u64)> = profiler
+            .iter_anchors()
+            .map(|stats| (stats.label.to_string(), stats.hits, stats.exclusive_ticks))
+            .collect();
+        assert_eq!(
+            stats,
+            [
+                ("connect".to_string(), 1, 5),
+                ("send".to_string(), 3, 6),
+                ("close".to_string(), 1, 1)
+            ]
+        );
+    }
+
+    #[test]
+    fn total_elapsed_works_as_a_stopwatch() {
+        let clock = MockTimeSource::new(1_000_000);
+        let mut profiler = Profiler::with_time_source(clock.clone());
+        assert!(!profiler.is_running());
+        assert_eq!(profiler.total_elapsed(), Duration::ZERO);
+
+        profiler.start();
+        assert!(profiler.is_running());
+        clock.advance(250_000);
+        assert_eq!(profiler.total_elapsed_ticks(), 250_000);
+        assert_eq!(profiler.total_elapsed(), Duration::from_millis(250));
+
+        profiler.stop();
+        clock.advance(1_000_000);
+        assert!(!profiler.is_running());
+        assert_eq!(profiler.total_elapsed(), Duration::from_millis(250));
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn runs_starting_or_ending_at_tick_zero_count() {
+        let clock = MockTimeSource::new(1000);
+        let mut profiler = Profiler::with_time_source(clock.clone());
+        clock.set(0);
+        profiler.start();
+        assert!(profiler.is_running());
+        clock.advance(40);
+        assert_eq!(profiler.total_elapsed_ticks(), 40);
+
+        let mut output = Vec::new();
+        profiler.print_results_to(&mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert!(!output.contains(NOT_STARTED_MESSAGE), "{output}");
+
+        clock.set(0);
+        profiler.start();
+        profiler.stop();
+        clock.advance(100);
+        assert!(!profiler.is_running());
+        assert_eq!(profiler.total_elapsed_ticks(), 0);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn total_elapsed_tracks_a_real_sleep() {
+        let mut profiler = Profiler::with_time_source(InstantTimeSource);
+        profiler.start();
+        std::thread::sleep(Duration::from_millis(50));
+        let elapsed = profiler.total_elapsed();
+        assert!(elapsed >= Duration::from_millis(50), "{elapsed:?}");
+        assert!(elapsed < Duration::from_millis(500), "{elapsed:?}");
+    }
+
+    #[cfg(feature = "profiling")]
+    #[test]
+    fn the_rate_is_read_once_per_start_and_recalibration() {
+        struct CountedRate {
+            clock: MockTimeSource,
+            reads: std::rc::Rc<Cell<u32>>,
+        }
+
+        impl TimeSource for CountedRate {
+            fn now_ticks(&self) -> u64 {
+                self.clock.now_ticks()
+            }
+
+            fn ticks_per_second(&self) -> u64 {
+                self.reads.set(self.reads.get() + 1);
+                self.clock.ticks_per_second()
+            }
+        }
+
+        let clock = MockTimeSource::new(1000);
+        let reads = std::rc::Rc::new(Cell::new(0));
+        let mut profiler = Profiler::with_time_source(CountedRate {
+            clock: clock.clone(),
+            reads: reads.clone(),
+        });
+        profiler.set_omit(Duration::from_millis(100));
+        profiler.start();
+        assert_eq!(reads.get(), 1);
+
+        for _ in 0..10 {
+            let block = profiler.begin_block_with_budget("send", Duration::from_millis(5));
+            clock.advance(20);
+            drop(block);
+        }
+        assert_eq!(profiler.report().anchors[0].hits, 5);
+        assert_eq!(reads.get(), 1);
+
+        profiler.recalibrate();
+        assert_eq!(reads.get(), 2);
+    }
+
+    #[cfg(feature = "profiling")]
+    #[test]
+    fn block_overhead_is_subtracted_from_every_hit() {
+        // Every clock read costs 7 ticks, so an empty block measures 7 ticks.
+        let clock = MockTimeSource::ticking(1_000_000, 7);
+        let mut profiler = Profiler::with_time_source(clock);
+        profiler.start();
+        assert_eq!(profiler.block_overhead_ticks(), 7);
+
+        for _ in 0..1_000_000 {
+            let _block = profiler.begin_block("empty");
+        }
+        let empty = profiler.anchor("empty").unwrap();
+        assert_eq!(empty.hits, 1_000_000);
+        assert_eq!((empty.exclusive_ticks, empty.inclusive_ticks), (0, 0));
+    }
+
+    #[cfg(all(feature = "std", not(feature = "profiling")))]
+    #[test]
+    fn without_profiling_blocks_are_zero_sized_no_ops() {
+        let (clock, mut profiler) = started(1000);
+        {
+            let block = profiler.begin_block_with_bandwidth("send", 64);
+            assert_eq!(std::mem::size_of_val(&block), 0);
+            assert!(!block.is_recording());
+            crate::profile!(profiler, "encode");
+            clock.advance(10);
+        }
+        assert_eq!(profiler.profile("compute", || 42), 42);
+
+        assert!(profiler.report().anchors.is_empty());
+        assert_eq!(profiler.iter_anchors().count(), 0);
+        assert_eq!(printed(&mut profiler), "");
+    }
+
+    #[cfg(all(feature = "std", feature = "profiling"))]
+    #[test]
+    fn backward_timer_reads_are_discarded() {
+        let (clock, mut profiler) = started(1000);
+        profiler.profile("steady", || clock.advance(20));
+        profiler.profile("migrated", || clock.set(clock.now_ticks() - 5));
+        clock.advance(100);
+
+        assert_eq!(profiler.discarded_samples(), 1);
+        assert_eq!(profiler.anchor("migrated"), None);
+        let report = profiler.report();
+        assert_eq!(report.discarded_samples, 1);
+        assert!(report
+            .anchors
+            .iter()
+            .all(|anchor| anchor.exclusive_ticks <= 20));
+
+        let output = printed(&mut profiler);
+        assert!(output.contains("    Discarded samples = 1 (timer went backwards)"));
+    }
+
+    #[cfg(feature = "profiling")]
+    #[test]
+    fn discarded_blocks_give_back_their_childrens_time() {
+        let (clock, profiler) = started(1000);
+        profiler.profile("migrated", || {
+            profiler.profile("copy", || clock.advance(40));
+            clock.set(clock.now_ticks() - 100);
+        });
+        profiler.profile("migrated", || clock.advance(30));
+        clock.advance(200);
+
+        assert_eq!(profiler.discarded_samples(), 1);
+        let migrated = profiler.anchor("migrated").unwrap();
+        assert_eq!((migrated.hits, migrated.exclusive_ticks), (1, 30));
+        #[cfg(debug_assertions)]
+        assert_eq!(profiler.validate(), Ok(()));
+    }
+
+    #[cfg(feature = "profiling")]
+    #[test]
+    fn intervals_report_deltas_not_totals() {
+        let (clock, mut profiler) = started(1000);
+
+        profiler.profile_with_bandwidth("send", 1000, || clock.advance(100));
+        clock.advance(900);
+        let first = profiler.report_interval();
+        assert_eq!(first.total_ticks, 1000);
+        assert_eq!((first.anchors[0].hits, first.anchors[0].bytes), (1, 1000));
+
+        for _ in 0..3 {
+            profiler.profile_with_bandwidth("send", 500, || clock.advance(50));
+        }
+        clock.advance(350);
+        let second = profiler.report_interval();
+        assert_eq!(second.total_ticks, 500);
+        let send = &second.anchors[0];
+        assert_eq!(
+            (send.hits, send.bytes, send.exclusive_ticks),
+            (3, 1500, 150)
+        );
+        assert_eq!(send.bytes_per_second, 10_000.0);
+
+        clock.advance(100);
+        assert!(profiler.report_interval().anchors.is_empty());
+        assert_eq!(profiler.report().anchors[0].bytes, 2500);
+    }
+
+    #[cfg(all(feature = "std", feature = "profiling"))]
+    #[test]
+    fn printing_after_stop_is_repeatable() {
+        let (clock, mut profiler) = started(1000);
+        profiler.profile("work", || clock.advance(40));
+        clock.advance(60);
+        profiler.stop();
+        assert!(!profiler.is_running());
+
+        clock.advance(500);
+        let first = printed_with(&mut profiler, Profiler::print_results);
+        clock.advance(500);
+        let second = printed_with(&mut profiler, Profiler::print_results);
+        assert!(first.contains("Total time = 100.0ms"));
+        assert_eq!(first, second);
+
+        profiler.stop();
+        assert_eq!(profiler.total_elapsed_ticks(), 100);
+    }
+
+    #[cfg(feature = "profiling")]
+    #[test]
+    fn reset_reuses_anchor_storage() {
+        let (clock, mut profiler) = started(1000);
+
+        let mut capacity = None;
+        for _ in 0..100 {
+            for label in ["connect", "send", "recv", "close"] {
+                profiler.profile(label, || clock.advance(1));
+            }
+            assert_eq!(profiler.anchor("send").unwrap().hits, 1);
+
+            profiler.reset();
+            assert!(profiler.anchor("send").is_none());
+            assert!(profiler.iter_anchors().next().is_none());
+            assert_eq!(profiler.total_elapsed_ticks(), 0);
+
+            let anchors = profiler.anchors.get_mut();
+            assert_eq!(anchors.len(), 1);
+            assert_eq!(
+                *capacity.get_or_insert(anchors.capacity()),
+                anchors.capacity()
+            );
+        }
+    }
+
+    #[test]
+    fn profile_timed_returns_the_closures_ticks() {
+        let (clock, profiler) = started(1000);
+
+        let (value, ticks) = profiler.profile_timed("send", || {
+            clock.advance(250);
+            7
+        });
+        assert_eq!((value, ticks), (7, 250));
+    }
+
+    #[cfg(all(feature = "std", feature = "profiling"))]
+    #[test]
+    fn blocks_past_their_budget_are_counted() {
+        let (clock, mut profiler) = started(1000);
+        for ticks in [5, 20, 10, 30] {
+            let block = profiler.begin_block_with_budget("send", Duration::from_millis(10));
+            clock.advance(ticks);
+            drop(block);
+        }
+        profiler.stop();
+
+        assert_eq!(profiler.anchor("send").unwrap().over_budget, 2);
+        let index = profiler.label_to_index.borrow()["send"];
+        assert!(profiler.anchors.borrow()[index].budget_warned);
+        let output = printed_with(&mut profiler, Profiler::print_results);
+        assert!(output.contains("[2 over budget]"), "{output}");
+
+        // Only the first overrun is printed, even across a reset of the anchor.
+        profiler.reset_anchor("send");
+        assert!(profiler.anchors.get_mut()[index].budget_warned);
+    }
+
+    #[cfg(all(feature = "profiling", debug_assertions))]
+    #[test]
+    #[should_panic(expected = "send took 30ms, over its 10ms budget")]
+    fn overruns_can_panic_in_debug_builds() {
+        let (clock, mut profiler) = started(1000);
+        profiler.set_panic_over_budget(true);
+        let block = profiler.begin_block_with_budget("send", Duration::from_millis(10));
+        clock.advance(30);
+        drop(block);
+    }
+
+    #[cfg(feature = "profiling")]
+    #[test]
+    fn warmup_hits_stay_out_of_the_timings() {
+        let (clock, mut profiler) = started(1000);
+        profiler.set_warmup(3);
+        for ticks in 1..=10 {
+            let block = profiler.begin_block("send");
+            clock.advance(ticks);
+            drop(block);
+        }
+        profiler.stop();
+
+        let send = profiler.anchor("send").unwrap();
+        assert_eq!((send.hits, send.warmup_hits), (7, 3));
+        // Only the 4..=10 tick hits count.
+        assert_eq!(send.exclusive_ms, 49.0);
+    }
+
+    #[cfg(feature = "profiling")]
+    #[test]
+    fn omitted_window_stays_out_of_the_summary() {
+        let (clock, mut profiler) = started(1000);
+        profiler.set_omit(Duration::from_millis(100));
+        let send = |bytes, ticks| {
+            let block = profiler.begin_block_with_bandwidth("send", bytes);
+            clock.advance(ticks);
+            drop(block);
+        };
+        send(4000, 60);
+        clock.advance(40);
+        send(1000, 50);
+        profiler.stop();
+
+        let summary = profiler.report();
+        assert_eq!(summary.total_ticks, 50);
+        assert_eq!(
+            (summary.anchors[0].hits, summary.anchors[0].bytes),
+            (1, 1000)
+        );
+
+        let interval = profiler.report_interval();
+        assert_eq!(interval.total_ticks, 150);
+        assert_eq!(interval.anchors[0].bytes, 5000);
+    }
+
+    #[cfg(feature = "profiling")]
+    #[test]
+    fn end_block_finishes_the_block_in_place() {
+        let (clock, profiler) = started(1000);
+        let block = profiler.begin_block("send");
+        clock.advance(20);
+        profiler.end_block(block);
+        clock.advance(30);
+
+        let send = profiler.anchor("send").unwrap();
+        assert_eq!((send.hits, send.inclusive_ticks), (1, 20));
+    }
+
+    #[cfg(feature = "profiling")]
+    #[test]
+    fn merge_sums_shared_labels_and_adds_the_rest() {
+        let clock = MockTimeSource::new(1000);
+        let mut first = Profiler::with_time_source(clock.clone());
+        let second = Profiler::with_time_source(clock.clone());
+        first.start();
+        let send = record(&first, "send", 30, 40, 2);
+        first.anchors.borrow_mut()[send].bytes_processed = 100;
+        record(&first, "encode", 10, 10, 1);
+        let send = record(&second, "send", 5, 6, 3);
+        second.anchors.borrow_mut()[send].bytes_processed = 50;
+        record(&second, "recv", 20, 20, 4);
+
+        first.merge(&second);
+        let stats = |label| {
+            let anchor = first.anchor(label).unwrap();
+            (
+                anchor.hits,
+                anchor.exclusive_ticks,
+                anchor.inclusive_ticks,
+                anchor.bytes,
+            )
+        };
+        assert_eq!(stats("send"), (5, 35, 46, 150));
+        assert_eq!(stats("encode"), (1, 10, 10, 0));
+        assert_eq!(stats("recv"), (4, 20, 20, 0));
+    }
+
+    #[cfg(all(feature = "profiling", debug_assertions))]
+    #[test]
+    fn validate_accepts_a_normal_run_and_flags_broken_counters() {
+        let (clock, mut profiler) = started(1000);
+        let outer = profiler.begin_block("outer");
+        clock.advance(10);
+        for _ in 0..3 {
+            let inner = profiler.begin_block("inner");
+            clock.advance(20);
+            drop(inner);
+        }
+        drop(outer);
+        clock.advance(5);
+        profiler.stop();
+        assert_eq!(profiler.validate(), Ok(()));
+
+        record(&profiler, "outer", 200, 70, 1);
+        let violations = profiler.validate().unwrap_err();
+        let label: Arc<str> = "outer".into();
+        assert!(violations.contains(&Violation::ExclusiveExceedsTotal {
+            label: label.clone(),
+            exclusive_ticks: 200
+        }));
+        assert!(violations.contains(&Violation::InclusiveBelowExclusive {
+            label,
+            exclusive_ticks: 200,
+            inclusive_ticks: 70
+        }));
+    }
+
+    #[cfg(all(feature = "std", feature = "profiling"))]
+    #[test]
+    fn tx_and_rx_bytes_are_totalled_separately() {
+        let (clock, mut profiler) = started(1000);
+        for (bytes, direction) in [
+            (3000, Direction::Tx),
+            (1000, Direction::Rx),
+            (500, Direction::Tx),
+        ] {
+            let block = profiler.begin_block_with_direction("udp", bytes, direction);
+            clock.advance(10);
+            drop(block);
+        }
+        drop(profiler.begin_block_with_bandwidth("udp", 100));
+        clock.advance(970);
+
+        let udp = profiler.anchor("udp").unwrap();
+        assert_eq!((udp.bytes, udp.tx_bytes, udp.rx_bytes), (4600, 3500, 1000));
+        let report = profiler.report();
+        assert_eq!(
+            (report.total_tx_bytes(), report.total_rx_bytes()),
+            (3500, 1000)
+        );
+
+        let output = printed(&mut profiler);
+        assert!(
+            output.contains("    TX = 0.003MBs at 0.00GB/s\n"),
+            "{output}"
+        );
+        assert!(
+            output.contains("    RX = 0.001MBs at 0.00GB/s\n"),
+            "{output}"
+        );
+    }
+
+    #[cfg(feature = "profiling")]
+    #[test]
+    fn smoothed_throughput_converges_after_a_step() {
+        let (clock, mut profiler) = started(1000);
+        profiler.set_throughput_smoothing(Some(0.5));
+        let mut smoothed = Vec::new();
+        for bytes in [1000, 3000, 3000, 3000, 3000, 3000] {
+            let block = profiler.begin_block_with_bandwidth("send", bytes);
+            clock.advance(10);
+            drop(block);
+            clock.advance(990);
+            profiler.report_interval();
+            smoothed.push(profiler.smoothed_throughput("send").unwrap());
+        }
+
+        assert_eq!(smoothed, [1000.0, 2000.0, 2500.0, 2750.0, 2875.0, 2937.5]);
+        assert_eq!(profiler.smoothed_throughput("recv"), None);
+    }
+
+    #[cfg(feature = "profiling")]
+    #[test]
+    fn anchor_ids_record_until_the_next_start() {
+        let (clock, mut profiler) = started(1000);
+        let send = profiler.register_anchor("send").unwrap();
+        assert_eq!(profiler.register_anchor("send"), Some(send));
+        for _ in 0..3 {
+            let block = profiler.begin_block_by_id_with_bandwidth(send, 100);
+            clock.advance(10);
+            drop(block);
+        }
+        let stats = profiler.anchor("send").unwrap();
+        assert_eq!(
+            (stats.hits, stats.inclusive_ticks, stats.bytes),
+            (3, 30, 300)
+        );
+
+        profiler.start();
+        assert!(!profiler.begin_block_by_id(send).is_recording());
+        assert_eq!(profiler.anchor("send"), None);
+    }
+
+    #[cfg(all(feature = "std", feature = "profiling"))]
+    #[test]
+    fn id_blocks_cost_no_more_than_label_blocks() {
+        let mut profiler = Profiler::with_time_source(InstantTimeSource);
+        profiler.start();
+        let id = profiler.register_anchor("per-packet receive path").unwrap();
+        // The cheapest of several rounds, in nanoseconds per block.
+        let cost = |block: &dyn Fn()| {
+            (0..5)
+                .map(|_| {
+                    let start = std::time::Instant::now();
+                    for _ in 0..20_000 {
+                        block();
+                    }
+                    start.elapsed().as_nanos() / 20_000
+                })
+                .min()
+                .unwrap()
+        };
+        let by_label = cost(&|| drop(profiler.begin_block("per-packet receive path")));
+        let by_id = cost(&|| drop(profiler.begin_block_by_id(id)));
+        assert!(by_id <= by_label * 2, "{by_id}ns vs {by_label}ns");
+    }
+
+    #[cfg(feature = "profiling")]
+    #[test]
+    fn block_end_callback_sees_every_block() {
+        let clock = MockTimeSource::new(1000);
+        let mut profiler = Profiler::with_time_source(clock.clone());
+        let ended = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let sink = ended.clone();
+        profiler.on_block_end(move |label, ticks, bytes| {
+            sink.lock().unwrap().push((label.to_string(), ticks, bytes));
+        });
+        profiler.start();
+        let send = profiler.begin_block_with_bandwidth("send", 512);
+        clock.advance(10);
+        profiler.profile("encode", || clock.advance(4));
+        drop(send);
+
+        assert_eq!(
+            *ended.lock().unwrap(),
+            [("encode".to_string(), 4, 0), ("send".to_string(), 14, 512)]
+        );
+    }
+
+    #[cfg(feature = "profiling")]
+    #[test]
+    fn ignored_labels_record_nothing() {
+        let (clock, mut profiler) = started(1000);
+        profiler.set_ignored(&["log"]);
+        for label in ["send", "log", "send"] {
+            let block = profiler.begin_block(label);
+            assert_eq!(block.is_recording(), label != "log");
+            clock.advance(5);
+            drop(block);
+        }
+
+        let labels: Vec<String> = profiler
+            .report()
+            .anchors
+            .into_iter()
+            .map(|anchor| anchor.label)
+            .collect();
+        assert_eq!(labels, ["send"]);
+        assert!(!profiler.label_to_index.borrow().contains_key("log"));
+        assert_eq!(profiler.register_anchor("log"), None);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn pure_work_inside_profile_is_not_optimized_away() {
+        let mut profiler = Profiler::with_time_source(CpuTimeSource::default());
+        profiler.start();
+
+        let n = 100_000u64;
+        let (sum, ticks) = profiler.profile_timed("sum_of_squares", || {
+            (0..n).fold(0u64, |acc, i| acc.wrapping_add(i.wrapping_mul(i)))
+        });
+        assert_eq!(sum, (0..n).map(|i| i * i).sum::<u64>());
+        assert!(ticks > 0);
+    }
+
+    #[cfg(feature = "profiling")]
+    #[test]
+    fn reset_anchor_keeps_only_later_hits() {
+        let (clock, mut profiler) = started(1000);
+        for _ in 0..3 {
+            profiler.profile("decode", || clock.advance(50));
+            profiler.profile("encode", || clock.advance(10));
+        }
+
+        profiler.reset_anchor("encode");
+        profiler.reset_anchor("missing");
+        for _ in 0..2 {
+            profiler.profile("encode", || clock.advance(20));
+        }
+
+        let encode = profiler.anchor("encode").unwrap();
+        assert_eq!((encode.hits, encode.exclusive_ticks), (2, 40));
+        let decode = profiler.anchor("decode").unwrap();
+        assert_eq!((decode.hits, decode.exclusive_ticks), (3, 150));
+    }
+
+    #[cfg(feature = "profiling")]
+    #[test]
+    fn backward_timer_between_intervals_is_discarded() {
+        let (clock, mut profiler) = started(1000);
+        profiler.profile("send", || clock.advance(40));
+        assert_eq!(profiler.report_interval().discarded_samples, 0);
+
+        clock.set(clock.now_ticks() - 30);
+        let report = profiler.report_interval();
+        assert_eq!(report.discarded_samples, 1);
+        assert_eq!(report.total_ticks, 0);
+
+        clock.advance(50);
+        let report = profiler.report_interval();
+        assert_eq!(report.total_ticks, 20);
+        assert_eq!(profiler.discarded_samples(), 1);
+    }
+
+    #[cfg(feature = "profiling")]
+    #[test]
+    fn sampled_hits_extrapolate_to_the_timed_total() {
+        let run = |every: u64| {
+            let (clock, mut profiler) = started(1000);
+            profiler.set_sampling("checksum", every);
+            for i in 0..1000 {
+                profiler.profile("checksum", || clock.advance(100 + i % 7 * 10));
+            }
+            let anchor = profiler.anchor("checksum").unwrap();
+            (anchor.hits, anchor.exclusive_ticks)
+        };
+
+        let (baseline_hits, baseline) = run(1);
+        let (sampled_hits, sampled) = run(10);
+        assert_eq!((baseline_hits, sampled_hits), (1000, 1000));
+        let error = (sampled as f64 - baseline as f64).abs() / baseline as f64;
+        assert!(error < 0.02, "{sampled} vs {baseline}");
+    }
+
+    #[cfg(feature = "profiling")]
+    #[test]
+    fn sampled_children_leave_the_parent_its_own_time() {
+        let (clock, mut profiler) = started(1000);
+        profiler.set_sampling("crc", 10);
+        profiler.profile("batch", || {
+            for _ in 0..10 {
+                profiler.profile("crc", || clock.advance(10));
+            }
+            clock.advance(20);
+        });
+
+        #[cfg(debug_assertions)]
+        assert_eq!(profiler.validate(), Ok(()));
+        let exclusive_ticks = |label: &str| profiler.anchor(label).unwrap().exclusive_ticks;
+        assert_eq!(exclusive_ticks("crc"), 100);
+        assert_eq!(exclusive_ticks("batch"), 20);
+    }
+
+    #[cfg(feature = "profiling")]
+    #[test]
+    fn sampled_parents_keep_their_children_apart() {
+        let (clock, mut profiler) = started(1000);
+        profiler.set_sampling("batch", 10);
+        profiler.profile("send", || {
+            for _ in 0..10 {
+                profiler.profile("batch", || {
+                    profiler.profile("crc", || clock.advance(10));
+                    clock.advance(20);
+                });
+            }
+            clock.advance(5);
+        });
+
+        #[cfg(debug_assertions)]
+        assert_eq!(profiler.validate(), Ok(()));
+        let exclusive_ticks = |label: &str| profiler.anchor(label).unwrap().exclusive_ticks;
+        assert_eq!(exclusive_ticks("crc"), 100);
+        assert_eq!(exclusive_ticks("batch"), 200);
+        assert_eq!(exclusive_ticks("send"), 5);
+    }
+
+    #[cfg(feature = "profiling")]
+    #[test]
+    fn min_comes_from_timed_hits_only() {
+        // The first hit is timed but taken by the warmup and the second is only
+        // counted, so the anchor has a hit before its first timed one.
+        let run = |ticks: &[u64]| {
+            let (clock, mut profiler) = started(1000);
+            profiler.set_warmup(1);
+            profiler.set_sampling("crc", 2);
+            for &ticks in ticks {
+                profiler.profile("crc", || clock.advance(ticks));
+            }
+            profiler
+        };
+        let min_ms = |profiler: &Profiler<MockTimeSource>| profiler.report().anchors[0].min_ms;
+
+        let profiler = run(&[5, 30, 40, 50, 60]);
+        assert_eq!(min_ms(&profiler), 40.0);
+
+        let mut untimed = run(&[5, 30]);
+        untimed.merge(&profiler);
+        assert_eq!(min_ms(&untimed), 40.0);
     }
 }
@@ -0,0 +1,82 @@
+//! Pacing a send loop to a target bitrate, like iperf's `-b`.
+
+use crate::{Profiler, TimeSource};
+use std::time::Duration;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BandwidthLimiter {
+    bits_per_second: u64,
+}
+
+impl BandwidthLimiter {
+    pub fn new(bits_per_second: u64) -> Self {
+        Self { bits_per_second }
+    }
+
+    pub fn bits_per_second(&self) -> u64 {
+        self.bits_per_second
+    }
+
+    /// How long to wait before sending more, having sent `bytes` in `elapsed`.
+    pub fn delay(&self, bytes: u64, elapsed: Duration) -> Duration {
+        if self.bits_per_second == 0 {
+            return Duration::ZERO;
+        }
+
+        let allowed_nanos = bytes as u128 * 8 * 1_000_000_000 / self.bits_per_second as u128;
+        let allowed = Duration::from_nanos(allowed_nanos.min(u64::MAX as u128) as u64);
+        allowed.saturating_sub(elapsed)
+    }
+
+    /// Sleeps until the bytes recorded under `label` fit the rate over the
+    /// profiler's elapsed time. Call it between sends of the profiled loop.
+    pub fn throttle<T: TimeSource>(&self, profiler: &Profiler<T>, label: &str) {
+        let bytes = profiler.anchor(label).map_or(0, |anchor| anchor.bytes);
+        let delay = self.delay(bytes, profiler.total_elapsed());
+        if !delay.is_zero() {
+            std::thread::sleep(delay);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delay_covers_the_bytes_sent_ahead_of_the_rate() {
+        let limiter = BandwidthLimiter::new(1_000_000);
+        // 125 kB is one second at 1 Mbit/s.
+        assert_eq!(
+            limiter.delay(125_000, Duration::from_millis(400)),
+            Duration::from_millis(600)
+        );
+        assert_eq!(
+            limiter.delay(125_000, Duration::from_secs(2)),
+            Duration::ZERO
+        );
+        assert_eq!(
+            BandwidthLimiter::new(0).delay(125_000, Duration::ZERO),
+            Duration::ZERO
+        );
+    }
+
+    #[cfg(feature = "profiling")]
+    #[test]
+    fn throttled_loop_runs_close_to_the_limit() {
+        let limiter = BandwidthLimiter::new(8_000_000);
+        let mut profiler = Profiler::with_time_source(crate::InstantTimeSource);
+        profiler.start();
+        for _ in 0..20 {
+            drop(profiler.begin_block_with_bandwidth("send", 5_000));
+            limiter.throttle(&profiler, "send");
+        }
+        profiler.stop();
+
+        let bits_per_second = 8.0 * 100_000.0 / profiler.total_elapsed().as_secs_f64();
+        assert!(bits_per_second <= 8_000_000.0 * 1.01, "{bits_per_second}");
+        // Each delay is measured from the start of the run, so oversleeping
+        // doesn't add up and a limiter that works keeps close to the rate.
+        assert!(bits_per_second >= 8_000_000.0 * 0.8, "{bits_per_second}");
+    }
+}
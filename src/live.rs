@@ -0,0 +1,156 @@
+//! A terminal view of a [`SharedProfiler`]'s top anchors that redraws itself
+//! from a background thread while the run goes on.
+//!
+//! Each frame is an interval report of the shared aggregate, so it shows what
+//! worker threads merged in since the previous frame. Workers keep recording
+//! into their own [`ThreadProfiler`](crate::ThreadProfiler)s and never wait
+//! on the view; a worker's hits reach it when the worker calls
+//! [`flush`](crate::ThreadProfiler::flush), which long-running workers should
+//! do every so often, or drops its thread profiler.
+
+use crate::report::ratio;
+use crate::{ProfileReport, SharedProfiler, TimeSource};
+use std::io::{self, stdout, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+const CLEAR_SCREEN: &str = "\x1b[2J\x1b[H";
+const LABEL_WIDTH: usize = 32;
+
+/// The running view; dropping it stops the redraws.
+pub struct LiveView {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl LiveView {
+    /// Stops the redraws and waits for the view's thread to finish.
+    pub fn stop(self) {}
+}
+
+impl Drop for LiveView {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn write_frame<W: Write>(w: &mut W, report: &ProfileReport, top: usize) -> io::Result<()> {
+    let seconds = report.total_ms / 1000.0;
+    writeln!(
+        w,
+        "{:<LABEL_WIDTH$} {:>10} {:>12} {:>8} {:>12}",
+        "label", "hits", "ms", "%", "MB/s"
+    )?;
+    for anchor in report.anchors.iter().take(top) {
+        let megabytes_per_second = ratio(anchor.bytes as f64, seconds) / (1024.0 * 1024.0);
+        let label: String = anchor.label.chars().take(LABEL_WIDTH).collect();
+        writeln!(
+            w,
+            "{label:<LABEL_WIDTH$} {:>10} {:>12.3} {:>8.2} {:>12.3}",
+            anchor.hits, anchor.exclusive_ms, anchor.percent, megabytes_per_second
+        )?;
+    }
+    Ok(())
+}
+
+impl<T: TimeSource + Clone + Send + Sync + 'static> SharedProfiler<T> {
+    /// Writes one frame of the view, the `top` anchors by exclusive time since
+    /// the previous frame, to `w`.
+    pub fn render_live_frame<W: Write>(&self, mut w: W, top: usize) -> io::Result<()> {
+        let report = self.aggregate().report_interval();
+        write_frame(&mut w, &report, top)
+    }
+
+    /// Clears the terminal and redraws the view on stdout every `refresh` until
+    /// the returned [`LiveView`] is dropped.
+    pub fn run_live_view(self: &Arc<Self>, refresh: Duration, top: usize) -> LiveView {
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let shared = Arc::clone(self);
+            let stop = Arc::clone(&stop);
+            std::thread::spawn(move || loop {
+                std::thread::sleep(refresh);
+                if stop.load(Ordering::Relaxed) {
+                    break;
+                }
+                let mut out = stdout().lock();
+                let _ = write!(out, "{CLEAR_SCREEN}");
+                let _ = shared.render_live_frame(&mut out, top);
+                let _ = out.flush();
+            })
+        };
+
+        LiveView {
+            stop,
+            thread: Some(thread),
+        }
+    }
+}
+
+#[cfg(all(test, feature = "profiling"))]
+mod tests {
+    use super::*;
+    use crate::timer::MockTimeSource;
+
+    #[test]
+    fn frame_shows_a_header_and_the_top_anchors() {
+        let clock = MockTimeSource::new(1000);
+        let shared = SharedProfiler::with_time_source(clock.clone());
+        shared.start();
+        {
+            let profiler = shared.thread_profiler();
+            for (label, ticks) in [("send", 30), ("encode", 20), ("log", 1)] {
+                let block = profiler.begin_block_with_bandwidth(label, 1024 * 1024);
+                clock.advance(ticks);
+                drop(block);
+            }
+        }
+        clock.advance(949);
+
+        let mut frame = Vec::new();
+        shared.render_live_frame(&mut frame, 2).unwrap();
+        let frame = String::from_utf8(frame).unwrap();
+        let lines: Vec<Vec<&str>> = frame
+            .lines()
+            .map(|line| line.split_whitespace().collect())
+            .collect();
+        assert_eq!(lines[0], ["label", "hits", "ms", "%", "MB/s"]);
+        assert_eq!(lines[1], ["send", "1", "30.000", "3.00", "1.000"]);
+        assert_eq!(lines[2][0], "encode");
+        assert_eq!(lines.len(), 3, "{frame}");
+
+        // The next frame only covers what was merged since.
+        let mut frame = Vec::new();
+        shared.render_live_frame(&mut frame, 2).unwrap();
+        assert_eq!(String::from_utf8(frame).unwrap().lines().count(), 1);
+    }
+
+    #[test]
+    fn frames_show_what_running_workers_flushed() {
+        let clock = MockTimeSource::new(1000);
+        let shared = SharedProfiler::with_time_source(clock.clone());
+        shared.start();
+        let frame = || {
+            let mut frame = Vec::new();
+            shared.render_live_frame(&mut frame, 5).unwrap();
+            String::from_utf8(frame).unwrap()
+        };
+
+        let mut profiler = shared.thread_profiler();
+        profiler.profile("recv", || clock.advance(10));
+        assert_eq!(frame().lines().count(), 1);
+
+        profiler.flush();
+        let frame = frame();
+        let recv = frame.lines().nth(1).unwrap();
+        assert_eq!(
+            recv.split_whitespace().take(2).collect::<Vec<_>>(),
+            ["recv", "1"]
+        );
+    }
+}
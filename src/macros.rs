@@ -0,0 +1,59 @@
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __function_name {
+    () => {{
+        fn f() {}
+        fn type_name_of<T>(_: T) -> &'static str {
+            ::core::any::type_name::<T>()
+        }
+        let name = type_name_of(f);
+        name.strip_suffix("::f").unwrap_or(name)
+    }};
+}
+
+/// Opens a block on `$profiler` that lasts until the end of the enclosing scope.
+///
+/// `profile!(profiler)` labels the block with the enclosing function's path,
+/// `profile!(profiler, "label")` uses an explicit label and
+/// `profile!(profiler, "label", bytes = n)` also records `n` processed bytes.
+#[macro_export]
+macro_rules! profile {
+    ($profiler:expr) => {
+        let _profile_guard = $profiler.begin_block($crate::__function_name!());
+    };
+    ($profiler:expr, $label:expr) => {
+        let _profile_guard = $profiler.begin_block($label);
+    };
+    ($profiler:expr, $label:expr, bytes = $bytes:expr) => {
+        let _profile_guard = $profiler.begin_block_with_bandwidth($label, $bytes);
+    };
+}
+
+#[cfg(all(test, feature = "profiling"))]
+mod tests {
+    use crate::timer::MockTimeSource;
+    use crate::Profiler;
+
+    fn parse(profiler: &Profiler<MockTimeSource>) {
+        profile!(profiler);
+        assert!(profiler
+            .label_to_index
+            .borrow()
+            .contains_key("iperf_rs::macros::tests::parse"));
+    }
+
+    #[test]
+    fn both_forms_open_a_block_for_the_scope() {
+        let profiler = Profiler::with_time_source(MockTimeSource::new(1000));
+        parse(&profiler);
+        assert_eq!(profiler.parent_index.get(), 0);
+
+        {
+            profile!(profiler, "send", bytes = 64);
+            assert_ne!(profiler.parent_index.get(), 0);
+        }
+        assert_eq!(profiler.parent_index.get(), 0);
+        let index = profiler.label_to_index.borrow()["send"];
+        assert_eq!(profiler.anchors.borrow()[index].bytes_processed, 64);
+    }
+}
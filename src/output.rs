@@ -0,0 +1,521 @@
+//! Where reports go with the standard library: stdout, stderr, the
+//! `PROFILE_OUT` file, a caller's `io::Write` sink or the `log` crate.
+//!
+//! Everything here reads the environment or writes through `std::io`; the
+//! profiler core only renders reports into a `core::fmt::Write`.
+
+use crate::report::{self, write_io, ProfileReport};
+use crate::{
+    CpuTimeSource, FoldedSamples, PercentBase, Profiler, ProfilerBuilder, TimeSource,
+    NOT_STARTED_MESSAGE,
+};
+#[cfg(feature = "fs")]
+use std::fs::{File, OpenOptions};
+#[cfg(feature = "fs")]
+use std::io::stderr;
+use std::io::{stdout, Stderr, Stdout};
+use std::io::{IsTerminal, Write};
+use std::sync::Once;
+use std::time::{Duration, SystemTime};
+
+#[cfg(feature = "fs")]
+const PROFILE_OUTPUT_ENV: &str = "PROFILE_OUT";
+#[cfg(feature = "fs")]
+const PROFILE_OUTPUT_APPEND_ENV: &str = "PROFILE_OUT_APPEND";
+
+pub(crate) enum ProfileOutput {
+    Stdout(Stdout),
+    Stderr(Stderr),
+    #[cfg(feature = "fs")]
+    File(File),
+    Sink(Box<dyn Write + Send>),
+    #[cfg(feature = "log")]
+    Log(LogLines),
+}
+
+// Buffers written text and logs each completed line as its own record.
+#[cfg(feature = "log")]
+pub(crate) struct LogLines {
+    level: log::Level,
+    pending: Vec<u8>,
+}
+
+#[cfg(feature = "log")]
+impl LogLines {
+    fn log_line(&self, line: &[u8]) {
+        log::log!(self.level, "{}", String::from_utf8_lossy(line));
+    }
+}
+
+#[cfg(feature = "log")]
+impl Write for LogLines {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.pending.extend_from_slice(buf);
+        while let Some(newline) = self.pending.iter().position(|&byte| byte == b'\n') {
+            self.log_line(&self.pending[..newline]);
+            self.pending.drain(..=newline);
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        if !self.pending.is_empty() {
+            self.log_line(&self.pending);
+            self.pending.clear();
+        }
+        Ok(())
+    }
+}
+
+impl Default for ProfileOutput {
+    fn default() -> Self {
+        ProfileOutput::Stdout(stdout())
+    }
+}
+
+impl ProfileOutput {
+    // Only an interactive stdout, and only unless NO_COLOR is set (https://no-color.org).
+    fn is_color_terminal(&self) -> bool {
+        matches!(self, ProfileOutput::Stdout(out) if out.is_terminal())
+            && std::env::var_os("NO_COLOR").is_none_or(|value| value.is_empty())
+    }
+}
+
+impl Write for ProfileOutput {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            ProfileOutput::Stdout(out) => out.write(buf),
+            ProfileOutput::Stderr(err) => err.write(buf),
+            #[cfg(feature = "fs")]
+            ProfileOutput::File(file) => file.write(buf),
+            ProfileOutput::Sink(sink) => sink.write(buf),
+            #[cfg(feature = "log")]
+            ProfileOutput::Log(lines) => lines.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            ProfileOutput::Stdout(out) => out.flush(),
+            ProfileOutput::Stderr(err) => err.flush(),
+            #[cfg(feature = "fs")]
+            ProfileOutput::File(file) => file.flush(),
+            ProfileOutput::Sink(sink) => sink.flush(),
+            #[cfg(feature = "log")]
+            ProfileOutput::Log(lines) => lines.flush(),
+        }
+    }
+}
+
+// Any value but empty or "0" asks for append mode.
+#[cfg(feature = "fs")]
+pub(crate) fn append_requested() -> bool {
+    std::env::var_os(PROFILE_OUTPUT_APPEND_ENV)
+        .is_some_and(|value| !value.is_empty() && value != "0")
+}
+
+#[cfg(feature = "fs")]
+fn open_profile_out(path: &str, append: bool) -> std::io::Result<File> {
+    if append {
+        OpenOptions::new().create(true).append(true).open(path)
+    } else {
+        File::create(path)
+    }
+}
+
+// The `PROFILE_OUT` file, or stdout when it is unset, and whether reports need
+// separators because they are appended to a file. A file that can't be opened
+// falls back to stderr with a warning.
+fn env_output(append: bool) -> (ProfileOutput, bool) {
+    #[cfg(not(feature = "fs"))]
+    let _ = append;
+    #[cfg(feature = "fs")]
+    if let Ok(path) = std::env::var(PROFILE_OUTPUT_ENV) {
+        return match open_profile_out(&path, append) {
+            Ok(file) => (ProfileOutput::File(file), append),
+            Err(err) => {
+                eprintln!("warning: can't create {PROFILE_OUTPUT_ENV} file {path}: {err}, reporting to stderr");
+                (ProfileOutput::Stderr(stderr()), false)
+            }
+        };
+    }
+
+    (ProfileOutput::default(), false)
+}
+
+impl<T: TimeSource> Drop for Profiler<T> {
+    fn drop(&mut self) {
+        if self.print_on_drop {
+            self.end_and_print_results();
+            let _ = self.log_file.flush();
+        }
+    }
+}
+
+impl Default for Profiler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        ProfilerBuilder::new().build()
+    }
+
+    pub fn builder() -> ProfilerBuilder {
+        ProfilerBuilder::new()
+    }
+
+    pub fn try_new() -> std::io::Result<Self> {
+        Self::try_with_time_source(CpuTimeSource::default())
+    }
+
+    pub fn with_calibration(calibration: Duration) -> Self {
+        ProfilerBuilder::new().calibration(calibration).build()
+    }
+}
+
+impl<T: TimeSource> Profiler<T> {
+    /// Reports go to the file named by `PROFILE_OUT`, or stdout when it is unset.
+    /// A file that can't be created falls back to stderr with a warning. With
+    /// `PROFILE_OUT_APPEND` set the file is appended to instead of truncated, and
+    /// every report starts with a timestamped separator. Without the `fs`
+    /// feature both variables are ignored and reports go to stdout.
+    pub fn with_time_source(time_source: T) -> Self {
+        #[cfg(not(feature = "fs"))]
+        let append = false;
+        #[cfg(feature = "fs")]
+        let append = append_requested();
+
+        Self::with_env_output(time_source, append)
+    }
+
+    pub(crate) fn with_env_output(time_source: T, append: bool) -> Self {
+        let (output, separate_reports) = env_output(append);
+        let mut profiler = Self::with_output(time_source, output);
+        profiler.separate_reports = separate_reports;
+        profiler
+    }
+
+    /// Like `with_time_source`, but fails if the `PROFILE_OUT` file can't be created.
+    pub fn try_with_time_source(time_source: T) -> std::io::Result<Self> {
+        #[cfg(feature = "fs")]
+        if let Ok(path) = std::env::var(PROFILE_OUTPUT_ENV) {
+            let append = append_requested();
+            let file = open_profile_out(&path, append)?;
+            let mut profiler = Self::with_output(time_source, ProfileOutput::File(file));
+            profiler.separate_reports = append;
+            return Ok(profiler);
+        }
+
+        Ok(Self::with_output(time_source, ProfileOutput::default()))
+    }
+
+    pub(crate) fn with_output(time_source: T, log_file: ProfileOutput) -> Self {
+        if !time_source.is_invariant() {
+            static WARN_VARIANT_TSC: Once = Once::new();
+            WARN_VARIANT_TSC.call_once(|| {
+                eprintln!("warning: the CPU timer is not invariant, profiling results may drift with frequency scaling");
+            });
+        }
+
+        let mut profiler = Self::with_defaults(time_source);
+        profiler.log_file = log_file;
+        profiler
+    }
+
+    /// Sends everything this profiler prints to the `log` crate at `level`
+    /// instead of stdout or the `PROFILE_OUT` file.
+    #[cfg(feature = "log")]
+    pub fn set_log_output(&mut self, level: log::Level) {
+        self.log_file = ProfileOutput::Log(LogLines {
+            level,
+            pending: Vec::new(),
+        });
+    }
+
+    /// Turns off the highlighting of hot anchors that printing to a terminal adds.
+    pub fn set_no_color(&mut self, no_color: bool) {
+        self.color = !no_color;
+    }
+
+    /// Prints reports to `sink` instead, e.g. a buffer or a socket.
+    pub fn set_output<W: Write + Send + 'static>(&mut self, sink: W) {
+        self.log_file = ProfileOutput::Sink(Box::new(sink));
+    }
+
+    /// Ends the run and prints the report when the profiler is dropped.
+    pub fn set_print_on_drop(&mut self, print_on_drop: bool) {
+        self.print_on_drop = print_on_drop;
+    }
+
+    /// Wall-clock time of the last `start` or `reset`.
+    pub fn started_at(&self) -> Option<SystemTime> {
+        self.started_at
+    }
+
+    pub fn print_interval(&mut self) {
+        let report = self.report_interval();
+        self.write_to_log(&report);
+    }
+
+    #[cfg(feature = "serde")]
+    pub fn write_json<W: Write>(&self, w: W) -> std::io::Result<()> {
+        self.report().write_json(w)
+    }
+
+    pub fn write_chrome_trace<W: Write>(&self, w: W) -> std::io::Result<()> {
+        self.raw_report().write_chrome_trace(w)
+    }
+
+    pub fn write_folded<W: Write>(&self, w: W, samples: FoldedSamples) -> std::io::Result<()> {
+        self.raw_report().write_folded(w, samples)
+    }
+
+    pub fn write_openmetrics<W: Write>(&self, w: W) -> std::io::Result<()> {
+        self.raw_report().write_openmetrics(w)
+    }
+
+    pub fn write_csv<W: Write>(&self, w: W) -> std::io::Result<()> {
+        self.report().write_csv(w)
+    }
+
+    pub fn write_influx_line<W: Write>(&self, w: W, measurement: &str) -> std::io::Result<()> {
+        self.report().write_influx_line(w, measurement)
+    }
+
+    pub fn print_top(&mut self, n: usize) {
+        let mut report = self.raw_report();
+        report.keep_top(n);
+        report.sort_by(self.sort_by);
+        self.write_to_log(&report);
+    }
+
+    pub fn print_filtered(&mut self, prefix: &str, base: PercentBase) {
+        let mut report = self.raw_report();
+        report.retain_prefix(prefix, base);
+        if let Some(n) = self.top_n {
+            report.keep_top(n);
+        }
+        report.sort_by(self.sort_by);
+        self.write_to_log(&report);
+    }
+
+    // Tells appended reports of successive runs apart.
+    fn write_separator(&mut self) {
+        if self.separate_reports {
+            let _ = writeln!(
+                self.log_file,
+                "==== Report at {} ====",
+                report::Rfc3339(SystemTime::now())
+            );
+        }
+    }
+
+    fn write_to_log(&mut self, report: &ProfileReport) {
+        self.write_separator();
+        if self.start_tsc.is_none() {
+            let _ = writeln!(self.log_file, "{NOT_STARTED_MESSAGE}");
+            return;
+        }
+
+        let color = self.color && self.log_file.is_color_terminal();
+        let (units, precision) = (self.report_units, self.precision);
+        let _ = write_io(&mut self.log_file, |w| {
+            report.format_text_colored(w, units, color, precision)
+        });
+    }
+
+    pub fn print_tree(&mut self) {
+        self.write_separator();
+        if self.start_tsc.is_none() {
+            let _ = writeln!(self.log_file, "{NOT_STARTED_MESSAGE}");
+            return;
+        }
+
+        let report = self.raw_report();
+        let _ = report.write_tree(&mut self.log_file);
+    }
+
+    pub fn print_results(&mut self) {
+        if !cfg!(feature = "profiling") {
+            return;
+        }
+
+        let report = self.report();
+        self.write_to_log(&report);
+    }
+
+    /// Renders the same report as `print_results`, into `w` instead of the log
+    /// output. See `write_results` for any `fmt::Write`.
+    pub fn print_results_to<W: Write>(&self, w: W) -> std::io::Result<()> {
+        write_io(w, |w| self.write_results(w))
+    }
+
+    #[inline]
+    pub fn end_and_print_results(&mut self) {
+        self.stop();
+        self.print_results();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(feature = "profiling")]
+    use crate::tests::printed_with;
+    use crate::tests::Captured;
+    use crate::timer::MockTimeSource;
+
+    #[test]
+    fn dropping_profiler_keeps_stdout_open() {
+        drop(Profiler::new());
+
+        // A zero-length write still fails with EBADF once fd 1 is closed.
+        let written = unsafe { nix::libc::write(1, [].as_ptr(), 0) };
+        assert_eq!(written, 0);
+        assert!(stdout().flush().is_ok());
+    }
+
+    #[test]
+    fn cpu_frequency_is_calibrated_once() {
+        let mut profiler = Profiler::new();
+        profiler.set_output(Captured::default());
+        profiler.start();
+        profiler.print_results();
+
+        let start = std::time::Instant::now();
+        profiler.print_results();
+        assert!(start.elapsed() < std::time::Duration::from_millis(50));
+    }
+
+    // Serializes the tests that point `PROFILE_OUT` somewhere.
+    #[cfg(feature = "fs")]
+    static PROFILE_OUT_LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+    #[cfg(not(feature = "fs"))]
+    #[test]
+    fn profile_out_is_ignored_without_fs() {
+        let path = std::env::temp_dir().join(format!("iperf-rs-{}-ignored", std::process::id()));
+        std::env::set_var("PROFILE_OUT", &path);
+        let profiler = Profiler::with_time_source(MockTimeSource::new(1000));
+        std::env::remove_var("PROFILE_OUT");
+
+        assert!(matches!(profiler.log_file, ProfileOutput::Stdout(_)));
+        assert!(!path.exists());
+    }
+
+    #[cfg(feature = "fs")]
+    #[test]
+    fn bad_profile_out_is_an_error_not_a_panic() {
+        let _lock = PROFILE_OUT_LOCK.lock().unwrap();
+        std::env::set_var(PROFILE_OUTPUT_ENV, "/nonexistent/iperf-rs/profile.txt");
+        let fallible = Profiler::try_with_time_source(MockTimeSource::new(1000));
+        let fallback = Profiler::with_time_source(MockTimeSource::new(1000));
+        std::env::remove_var(PROFILE_OUTPUT_ENV);
+
+        let err = fallible.err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+        assert!(matches!(fallback.log_file, ProfileOutput::Stderr(_)));
+    }
+
+    #[cfg(feature = "log")]
+    struct CapturingLogger(std::sync::Mutex<Vec<(log::Level, String)>>);
+
+    #[cfg(feature = "log")]
+    impl log::Log for CapturingLogger {
+        fn enabled(&self, _metadata: &log::Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &log::Record) {
+            let line = (record.level(), record.args().to_string());
+            self.0.lock().unwrap().push(line);
+        }
+
+        fn flush(&self) {}
+    }
+
+    #[cfg(feature = "log")]
+    #[test]
+    fn reports_can_go_through_the_log_crate() {
+        static LOGGER: CapturingLogger = CapturingLogger(std::sync::Mutex::new(Vec::new()));
+        log::set_logger(&LOGGER).unwrap();
+        log::set_max_level(log::LevelFilter::Trace);
+
+        let (clock, mut profiler) = crate::tests::started(1000);
+        profiler.set_log_output(log::Level::Debug);
+        crate::tests::record(&profiler, "send", 10, 10, 1);
+        clock.advance(20);
+        profiler.print_results();
+
+        let lines = LOGGER.0.lock().unwrap();
+        assert_eq!(
+            lines[0],
+            (log::Level::Debug, "Performance report:".to_string())
+        );
+        assert!(lines.contains(&(
+            log::Level::Debug,
+            "send[1]: 10.00ms (50.00%, - of parent) 50hits/s".to_string()
+        )));
+    }
+
+    #[cfg(feature = "profiling")]
+    #[test]
+    fn printing_before_start_says_so() {
+        let clock = MockTimeSource::new(1000);
+        let mut profiler = Profiler::with_time_source(clock.clone());
+        clock.advance(500);
+
+        let output = printed_with(&mut profiler, Profiler::print_results);
+        assert_eq!(output, format!("{NOT_STARTED_MESSAGE}\n"));
+
+        let mut buffer = Vec::new();
+        profiler.print_results_to(&mut buffer).unwrap();
+        assert_eq!(String::from_utf8(buffer).unwrap(), output);
+    }
+
+    #[test]
+    fn reports_carry_the_wall_clock_start() {
+        let mut profiler = Profiler::with_time_source(MockTimeSource::new(1000));
+        assert_eq!(profiler.report().started_at, None);
+
+        let before = std::time::SystemTime::now();
+        profiler.start();
+        let started_at = profiler.report().started_at.unwrap();
+        let offset = started_at.duration_since(before).unwrap();
+        assert!(offset < Duration::from_secs(1), "{offset:?}");
+    }
+
+    #[cfg(all(feature = "fs", feature = "profiling"))]
+    #[test]
+    fn appended_reports_accumulate_in_profile_out() {
+        let path = std::env::temp_dir().join(format!("iperf-rs-{}-append", std::process::id()));
+        std::fs::write(&path, "earlier run\n").unwrap();
+
+        let _lock = PROFILE_OUT_LOCK.lock().unwrap();
+        std::env::set_var(PROFILE_OUTPUT_ENV, &path);
+        std::env::set_var(PROFILE_OUTPUT_APPEND_ENV, "1");
+        for label in ["first", "second"] {
+            let (clock, mut profiler) = crate::tests::started(1000);
+            profiler.profile(label, || clock.advance(10));
+            profiler.end_and_print_results();
+        }
+        std::env::remove_var(PROFILE_OUTPUT_APPEND_ENV);
+        std::env::remove_var(PROFILE_OUTPUT_ENV);
+
+        let output = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(
+            output.starts_with("earlier run\n==== Report at "),
+            "{output}"
+        );
+        assert_eq!(output.matches("==== Report at ").count(), 2, "{output}");
+        assert_eq!(output.matches("Performance report:").count(), 2, "{output}");
+        assert!(
+            output.find("first[1]") < output.find("second[1]"),
+            "{output}"
+        );
+    }
+}
@@ -0,0 +1,114 @@
+//! Independent profiling sessions looked up by name.
+//!
+//! Each session is a separate [`Profiler`] with its own anchors, so two code
+//! paths can be timed side by side in one process and reported on their own.
+
+use crate::{CpuTimeSource, ProfileOutput, ProfileReport, Profiler, TimeSource};
+use std::collections::BTreeMap;
+use std::io::{stdout, Write};
+
+pub struct ProfilerRegistry<T: TimeSource = CpuTimeSource> {
+    sessions: BTreeMap<String, Profiler<T>>,
+    time_source: T,
+}
+
+impl ProfilerRegistry {
+    pub fn new() -> Self {
+        Self::with_time_source(CpuTimeSource::default())
+    }
+}
+
+impl Default for ProfilerRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: TimeSource + Clone> ProfilerRegistry<T> {
+    pub fn with_time_source(time_source: T) -> Self {
+        Self {
+            sessions: BTreeMap::new(),
+            time_source,
+        }
+    }
+
+    /// The session called `name`, created unstarted on first use. Sessions print to stdout.
+    pub fn session(&mut self, name: &str) -> &mut Profiler<T> {
+        if !self.sessions.contains_key(name) {
+            let profiler =
+                Profiler::with_output(self.time_source.clone(), ProfileOutput::Stdout(stdout()));
+            self.sessions.insert(name.to_string(), profiler);
+        }
+        self.sessions.get_mut(name).unwrap()
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Profiler<T>> {
+        self.sessions.get(name)
+    }
+
+    pub fn remove(&mut self, name: &str) -> Option<Profiler<T>> {
+        self.sessions.remove(name)
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.sessions.keys().map(String::as_str)
+    }
+
+    pub fn report(&self, name: &str) -> Option<ProfileReport> {
+        self.get(name).map(Profiler::report)
+    }
+
+    /// Reports of every session, ordered by name.
+    pub fn reports(&self) -> Vec<(String, ProfileReport)> {
+        self.sessions
+            .iter()
+            .map(|(name, profiler)| (name.clone(), profiler.report()))
+            .collect()
+    }
+
+    pub fn print_results(&mut self) {
+        for (name, profiler) in &mut self.sessions {
+            let _ = writeln!(profiler.log_file, "Session {name}:");
+            profiler.print_results();
+        }
+    }
+}
+
+#[cfg(all(test, feature = "profiling"))]
+mod tests {
+    use super::*;
+    use crate::timer::MockTimeSource;
+
+    #[test]
+    fn sessions_keep_separate_anchors() {
+        let clock = MockTimeSource::new(1000);
+        let mut registry = ProfilerRegistry::with_time_source(clock.clone());
+        registry.session("splice").start();
+        registry.session("read-write").start();
+
+        registry
+            .session("splice")
+            .profile("splice", || clock.advance(10));
+        let read_write = registry.session("read-write");
+        read_write.profile("read", || clock.advance(20));
+        read_write.profile("write", || clock.advance(30));
+
+        let labels = |report: ProfileReport| -> Vec<String> {
+            report
+                .anchors
+                .into_iter()
+                .map(|anchor| anchor.label)
+                .collect()
+        };
+        assert_eq!(labels(registry.report("splice").unwrap()), ["splice"]);
+        assert_eq!(
+            labels(registry.report("read-write").unwrap()),
+            ["write", "read"]
+        );
+        assert!(registry.report("missing").is_none());
+        assert_eq!(
+            registry.names().collect::<Vec<_>>(),
+            ["read-write", "splice"]
+        );
+    }
+}
@@ -0,0 +1,1173 @@
+use crate::DatagramReport;
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::cmp::Reverse;
+use core::fmt::{self, Write};
+#[cfg(feature = "std")]
+use std::io;
+#[cfg(feature = "std")]
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ReportUnits {
+    #[default]
+    Bytes,
+    Bits,
+    Both,
+}
+
+const OTHER_LABEL: &str = "(other)";
+pub(crate) const ROOT_LABEL: &str = "(root)";
+const HOT_PERCENT: f64 = 25.0;
+const WARM_PERCENT: f64 = 10.0;
+const ANSI_RED: &str = "\x1b[31m";
+const ANSI_YELLOW: &str = "\x1b[33m";
+const ANSI_RESET: &str = "\x1b[0m";
+// Bump whenever the saved layout of `ProfileReport` changes.
+#[cfg(all(feature = "serde", feature = "fs"))]
+const REPORT_FORMAT_VERSION: u64 = 8;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SortBy {
+    #[default]
+    ExclusiveTime,
+    InclusiveTime,
+    Hits,
+    Label,
+    Throughput,
+}
+
+/// What percentages are relative to once a report has been filtered.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PercentBase {
+    #[default]
+    Total,
+    Filtered,
+}
+
+// `numerator / denominator`, or 0 when there is nothing to divide by, so an empty
+// or instantaneous run reports zeros instead of NaN or infinity.
+pub(crate) fn ratio(numerator: f64, denominator: f64) -> f64 {
+    if denominator == 0.0 {
+        0.0
+    } else {
+        numerator / denominator
+    }
+}
+
+// Displays a millisecond value in whichever of ns/µs/ms/s keeps it readable,
+// with four significant digits, or with the formatter's precision as decimals.
+pub(crate) struct ScaledMs(pub(crate) f64);
+
+impl fmt::Display for ScaledMs {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ms = self.0;
+        let (value, unit) = if ms == 0.0 {
+            return write!(f, "0ns");
+        } else if ms.abs() < 0.001 {
+            (ms * 1_000_000.0, "ns")
+        } else if ms.abs() < 1.0 {
+            (ms * 1000.0, "µs")
+        } else if ms.abs() < 1000.0 {
+            (ms, "ms")
+        } else {
+            (ms / 1000.0, "s")
+        };
+
+        let decimals = f.precision().unwrap_or(match value.abs() {
+            v if v < 10.0 => 3,
+            v if v < 100.0 => 2,
+            v if v < 1000.0 => 1,
+            _ => 0,
+        });
+        write!(f, "{value:.decimals$}{unit}")
+    }
+}
+
+// Displays a wall-clock time as an RFC 3339 UTC timestamp with milliseconds.
+#[cfg(feature = "std")]
+pub(crate) struct Rfc3339(pub(crate) SystemTime);
+
+#[cfg(feature = "std")]
+impl fmt::Display for Rfc3339 {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let since_epoch = self.0.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let secs = since_epoch.as_secs();
+        let (days, secs_of_day) = (secs / 86_400, secs % 86_400);
+
+        // Civil date from days since 1970-01-01, after Howard Hinnant's `civil_from_days`.
+        let z = days as i64 + 719_468;
+        let era = z.div_euclid(146_097);
+        let day_of_era = z.rem_euclid(146_097);
+        let year_of_era =
+            (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let mp = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+        write!(
+            f,
+            "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}.{:03}Z",
+            secs_of_day / 3600,
+            secs_of_day / 60 % 60,
+            secs_of_day % 60,
+            since_epoch.subsec_millis()
+        )
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct AnchorReport {
+    pub label: String,
+    pub parent: Option<String>,
+    pub callers: Vec<String>,
+    /// Distinct parents it was entered under, the top level counting as one.
+    pub call_sites: usize,
+    pub hits: u64,
+    pub exclusive_ticks: u64,
+    pub inclusive_ticks: u64,
+    pub exclusive_ms: f64,
+    pub inclusive_ms: f64,
+    pub min_ms: f64,
+    pub max_ms: f64,
+    pub last_ms: f64,
+    pub p50_ms: Option<f64>,
+    pub p90_ms: Option<f64>,
+    pub p99_ms: Option<f64>,
+    pub mean_ms: Option<f64>,
+    pub stddev_ms: Option<f64>,
+    pub percent: f64,
+    pub percent_with_children: f64,
+    /// Share of the parent's inclusive time spent in hits under it, over the
+    /// whole run like min/max; None for top-level anchors.
+    pub percent_of_parent: Option<f64>,
+    pub bytes: u64,
+    /// The part of `bytes` from blocks begun with a direction.
+    pub tx_bytes: u64,
+    pub rx_bytes: u64,
+    /// Hits of budgeted blocks that ran past their budget.
+    pub over_budget: u64,
+    /// Hits left out of the timings by `Profiler::set_warmup`.
+    pub warmup_hits: u64,
+    pub bytes_per_second: f64,
+    pub bytes_per_hit: f64,
+    /// Inclusive ticks per byte; with the hardware timer ticks are CPU cycles.
+    /// None for anchors without bytes.
+    pub cycles_per_byte: Option<f64>,
+    /// Hits over the whole report's total time, e.g. a packet rate.
+    pub hits_per_second: f64,
+}
+
+fn write_ms<W: Write>(w: &mut W, ms: f64, precision: Option<usize>) -> fmt::Result {
+    match precision {
+        Some(precision) => write!(w, "{:.precision$}", ScaledMs(ms)),
+        None => write!(w, "{}", ScaledMs(ms)),
+    }
+}
+
+fn write_throughput<W: Write>(
+    w: &mut W,
+    bytes: u64,
+    bytes_per_second: f64,
+    units: ReportUnits,
+    precision: Option<usize>,
+) -> fmt::Result {
+    let mb = 1024.0 * 1024.0;
+    let gb = mb * 1024.0;
+
+    let megabytes = bytes as f64 / mb;
+    let gigabytes_per_second = bytes_per_second / gb;
+    let megabits_per_second = bytes_per_second * 8.0 / 1_000_000.0;
+    let gigabits_per_second = megabits_per_second / 1000.0;
+
+    let (size_decimals, rate_decimals) = (precision.unwrap_or(3), precision.unwrap_or(2));
+    write!(w, "{megabytes:.size_decimals$}MBs at")?;
+    if units != ReportUnits::Bits {
+        write!(w, " {gigabytes_per_second:.rate_decimals$}GB/s")?;
+    }
+    if units == ReportUnits::Both {
+        write!(w, ",")?;
+    }
+    if units != ReportUnits::Bytes {
+        write!(
+            w,
+            " {megabits_per_second:.rate_decimals$}Mbits/sec ({gigabits_per_second:.rate_decimals$}Gbits/sec)"
+        )?;
+    }
+    Ok(())
+}
+
+impl AnchorReport {
+    pub(crate) fn empty(label: &str) -> Self {
+        Self {
+            label: label.to_string(),
+            parent: None,
+            callers: Vec::new(),
+            call_sites: 0,
+            hits: 0,
+            exclusive_ticks: 0,
+            inclusive_ticks: 0,
+            exclusive_ms: 0.0,
+            inclusive_ms: 0.0,
+            min_ms: 0.0,
+            max_ms: 0.0,
+            last_ms: 0.0,
+            p50_ms: None,
+            p90_ms: None,
+            p99_ms: None,
+            mean_ms: None,
+            stddev_ms: None,
+            percent: 0.0,
+            percent_with_children: 0.0,
+            percent_of_parent: None,
+            bytes: 0,
+            tx_bytes: 0,
+            rx_bytes: 0,
+            over_budget: 0,
+            warmup_hits: 0,
+            bytes_per_second: 0.0,
+            bytes_per_hit: 0.0,
+            cycles_per_byte: None,
+            hits_per_second: 0.0,
+        }
+    }
+
+    pub fn has_children(&self) -> bool {
+        self.exclusive_ticks != self.inclusive_ticks
+    }
+
+    fn write_text<W: Write>(
+        &self,
+        w: &mut W,
+        units: ReportUnits,
+        color: bool,
+        precision: Option<usize>,
+    ) -> fmt::Result {
+        let highlight = match self.percent {
+            _ if !color => None,
+            percent if percent > HOT_PERCENT => Some(ANSI_RED),
+            percent if percent > WARM_PERCENT => Some(ANSI_YELLOW),
+            _ => None,
+        };
+        if let Some(highlight) = highlight {
+            write!(w, "{highlight}")?;
+        }
+
+        write!(w, "{}[{}]: ", self.label, self.hits)?;
+        write_ms(w, self.exclusive_ms, precision)?;
+        write!(w, " ({:.2}%", self.percent)?;
+
+        if self.has_children() {
+            write!(w, ", {:.2}% w/children", self.percent_with_children)?;
+        }
+        match self.percent_of_parent {
+            Some(percent) => write!(w, ", {percent:.2}% of parent")?,
+            None => write!(w, ", - of parent")?,
+        }
+        write!(w, ") {:.0}hits/s", self.hits_per_second)?;
+        if let (Some(mean_ms), Some(stddev_ms)) = (self.mean_ms, self.stddev_ms) {
+            write!(w, " ")?;
+            write_ms(w, mean_ms, precision)?;
+            write!(w, "±")?;
+            write_ms(w, stddev_ms, precision)?;
+        }
+
+        if self.bytes != 0 {
+            write!(w, " ")?;
+            write_throughput(w, self.bytes, self.bytes_per_second, units, precision)?;
+            write!(w, ", {:.0}B/hit", self.bytes_per_hit)?;
+            if let Some(cycles_per_byte) = self.cycles_per_byte {
+                write!(w, ", {cycles_per_byte:.2} cycles/B")?;
+            }
+        }
+
+        if self.call_sites > 1 {
+            write!(w, " called from {} sites", self.call_sites)?;
+        }
+        if self.over_budget != 0 {
+            write!(w, " [{} over budget]", self.over_budget)?;
+        }
+
+        if highlight.is_some() {
+            write!(w, "{ANSI_RESET}")?;
+        }
+        writeln!(w)
+    }
+
+    fn absorb(&mut self, other: &AnchorReport) {
+        self.min_ms = if self.hits == 0 {
+            other.min_ms
+        } else {
+            self.min_ms.min(other.min_ms)
+        };
+        self.max_ms = self.max_ms.max(other.max_ms);
+        self.call_sites = self.call_sites.max(other.call_sites);
+        self.hits += other.hits;
+        self.exclusive_ticks += other.exclusive_ticks;
+        self.inclusive_ticks += other.inclusive_ticks;
+        self.exclusive_ms += other.exclusive_ms;
+        self.inclusive_ms += other.inclusive_ms;
+        self.percent += other.percent;
+        self.percent_with_children += other.percent_with_children;
+        self.bytes += other.bytes;
+        self.tx_bytes += other.tx_bytes;
+        self.rx_bytes += other.rx_bytes;
+        self.over_budget += other.over_budget;
+        self.warmup_hits += other.warmup_hits;
+        if self.inclusive_ms > 0.0 {
+            self.bytes_per_second = self.bytes as f64 / (self.inclusive_ms / 1000.0);
+        }
+        self.bytes_per_hit = ratio(self.bytes as f64, self.hits as f64);
+        self.cycles_per_byte =
+            (self.bytes != 0).then(|| self.inclusive_ticks as f64 / self.bytes as f64);
+        self.hits_per_second += other.hits_per_second;
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ProfileReport {
+    pub cpu_freq: u64,
+    pub total_ticks: u64,
+    pub total_ms: f64,
+    pub anchors: Vec<AnchorReport>,
+    pub other: Option<AnchorReport>,
+    /// The whole run as one hit: inclusive is the total time and exclusive the
+    /// part spent outside every block, so all exclusive times add up to the total.
+    pub root: AnchorReport,
+    /// Blocks whose end tick came before their start tick, e.g. after migrating
+    /// to a core with an unsynchronized counter. They record no hit.
+    pub discarded_samples: u64,
+    /// Wall-clock time `start` was called, for lining the run up with other logs.
+    #[cfg(feature = "std")]
+    pub started_at: Option<SystemTime>,
+    /// Wall-clock time `stop` was called, or the report was taken if the run is
+    /// still going; None like `started_at` before `start`.
+    #[cfg(feature = "std")]
+    pub ended_at: Option<SystemTime>,
+    /// Loss and jitter of the datagrams recorded since `start`, if any were.
+    pub datagrams: Option<DatagramReport>,
+}
+
+impl ProfileReport {
+    /// Bytes processed by all anchors together.
+    pub fn total_bytes(&self) -> u64 {
+        self.anchors
+            .iter()
+            .chain(&self.other)
+            .map(|anchor| anchor.bytes)
+            .sum()
+    }
+
+    /// Bytes of all blocks begun with [`Direction::Tx`](crate::Direction::Tx).
+    pub fn total_tx_bytes(&self) -> u64 {
+        self.anchors
+            .iter()
+            .chain(&self.other)
+            .map(|anchor| anchor.tx_bytes)
+            .sum()
+    }
+
+    /// Bytes of all blocks begun with [`Direction::Rx`](crate::Direction::Rx).
+    pub fn total_rx_bytes(&self) -> u64 {
+        self.anchors
+            .iter()
+            .chain(&self.other)
+            .map(|anchor| anchor.rx_bytes)
+            .sum()
+    }
+
+    /// Overall throughput: `total_bytes` over the total run time.
+    pub fn bytes_per_second(&self) -> f64 {
+        ratio(self.total_bytes() as f64, self.total_ms / 1000.0)
+    }
+
+    /// One `key=value` line for scraping out of logs, with keys in this order:
+    /// `total_ms`, `top_label` (the anchor with the most exclusive time, quoted
+    /// if it holds spaces, `=` or quotes, empty without anchors), `top_pct` and
+    /// `total_MBps`, the aggregate throughput in MiB/s.
+    pub fn summary_line(&self) -> String {
+        let top = self
+            .anchors
+            .iter()
+            .chain(&self.other)
+            .max_by_key(|anchor| anchor.exclusive_ticks);
+        let top_label = top.map_or("", |anchor| anchor.label.as_str());
+        let top_label = if top_label.contains(|c: char| c.is_whitespace() || c == '=' || c == '"') {
+            format!("{top_label:?}")
+        } else {
+            top_label.to_string()
+        };
+
+        format!(
+            "total_ms={:.3} top_label={top_label} top_pct={:.2} total_MBps={:.3}",
+            self.total_ms,
+            top.map_or(0.0, |anchor| anchor.percent),
+            self.bytes_per_second() / (1024.0 * 1024.0)
+        )
+    }
+
+    /// Drops every anchor whose label doesn't start with `prefix`.
+    pub fn retain_prefix(&mut self, prefix: &str, base: PercentBase) {
+        self.anchors
+            .retain(|anchor| anchor.label.starts_with(prefix));
+        self.other = None;
+
+        if base == PercentBase::Filtered {
+            let filtered_ticks: u64 = self
+                .anchors
+                .iter()
+                .map(|anchor| anchor.exclusive_ticks)
+                .sum();
+            for anchor in &mut self.anchors {
+                anchor.percent =
+                    100.0 * ratio(anchor.exclusive_ticks as f64, filtered_ticks as f64);
+                anchor.percent_with_children =
+                    100.0 * ratio(anchor.inclusive_ticks as f64, filtered_ticks as f64);
+            }
+        }
+    }
+
+    /// Keeps the `n` anchors with the most exclusive time and folds the rest into `other`.
+    pub fn keep_top(&mut self, n: usize) {
+        if self.anchors.len() <= n {
+            return;
+        }
+
+        self.anchors
+            .sort_by_key(|anchor| Reverse(anchor.exclusive_ticks));
+        let rest = self.anchors.split_off(n);
+        let other = self
+            .other
+            .get_or_insert_with(|| AnchorReport::empty(OTHER_LABEL));
+        for anchor in &rest {
+            other.absorb(anchor);
+        }
+    }
+
+    /// Orders anchors by `sort`, largest first for every key except `Label`.
+    pub fn sort_by(&mut self, sort: SortBy) {
+        match sort {
+            SortBy::ExclusiveTime => self
+                .anchors
+                .sort_by_key(|anchor| Reverse(anchor.exclusive_ticks)),
+            SortBy::InclusiveTime => self
+                .anchors
+                .sort_by_key(|anchor| Reverse(anchor.inclusive_ticks)),
+            SortBy::Hits => self.anchors.sort_by_key(|anchor| Reverse(anchor.hits)),
+            SortBy::Label => self.anchors.sort_by(|a, b| a.label.cmp(&b.label)),
+            SortBy::Throughput => self
+                .anchors
+                .sort_by(|a, b| b.bytes_per_second.total_cmp(&a.bytes_per_second)),
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    pub fn write_json<W: io::Write>(&self, w: W) -> io::Result<()> {
+        serde_json::to_writer(w, self)?;
+        Ok(())
+    }
+
+    /// Writes the report as versioned JSON that `load` reads back.
+    #[cfg(all(feature = "serde", feature = "fs"))]
+    pub fn save<P: AsRef<std::path::Path>>(&self, path: P) -> io::Result<()> {
+        let saved = serde_json::json!({
+            "version": REPORT_FORMAT_VERSION,
+            "report": self,
+        });
+        let file = io::BufWriter::new(std::fs::File::create(path)?);
+        serde_json::to_writer(file, &saved)?;
+        Ok(())
+    }
+
+    #[cfg(all(feature = "serde", feature = "fs"))]
+    pub fn load<P: AsRef<std::path::Path>>(path: P) -> io::Result<Self> {
+        let file = io::BufReader::new(std::fs::File::open(path)?);
+        let mut saved: serde_json::Value = serde_json::from_reader(file)?;
+        match saved["version"].as_u64() {
+            Some(REPORT_FORMAT_VERSION) => Ok(serde_json::from_value(saved["report"].take())?),
+            version => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "unsupported report format version {version:?}, expected {REPORT_FORMAT_VERSION}"
+                ),
+            )),
+        }
+    }
+
+    #[cfg(feature = "std")]
+    pub fn write_text<W: io::Write>(&self, w: &mut W, units: ReportUnits) -> io::Result<()> {
+        write_io(w, |w| self.format_text(w, units))
+    }
+
+    /// Renders the text report into any `fmt::Write`, e.g. a `String`.
+    pub fn format_text<W: Write>(&self, w: &mut W, units: ReportUnits) -> fmt::Result {
+        self.format_text_colored(w, units, false, None)
+    }
+
+    // Like `format_text`, highlighting anchors that take a large share of the
+    // total and printing times and throughput with `precision` decimals when set.
+    pub(crate) fn format_text_colored<W: Write>(
+        &self,
+        w: &mut W,
+        units: ReportUnits,
+        color: bool,
+        precision: Option<usize>,
+    ) -> fmt::Result {
+        writeln!(w, "Performance report:")?;
+        writeln!(w, "    CPU frequency: {}hz", self.cpu_freq)?;
+        #[cfg(feature = "std")]
+        if let Some(started_at) = self.started_at {
+            writeln!(w, "    Started at {}", Rfc3339(started_at))?;
+        }
+        write!(w, "    Total time = ")?;
+        write_ms(w, self.total_ms, precision)?;
+        writeln!(w)?;
+        let total_bytes = self.total_bytes();
+        if total_bytes != 0 {
+            write!(w, "    Throughput = ")?;
+            write_throughput(w, total_bytes, self.bytes_per_second(), units, precision)?;
+            writeln!(w)?;
+        }
+        let total_seconds = self.total_ms / 1000.0;
+        for (direction, bytes) in [("TX", self.total_tx_bytes()), ("RX", self.total_rx_bytes())] {
+            if bytes != 0 {
+                write!(w, "    {direction} = ")?;
+                write_throughput(
+                    w,
+                    bytes,
+                    ratio(bytes as f64, total_seconds),
+                    units,
+                    precision,
+                )?;
+                writeln!(w)?;
+            }
+        }
+        if let Some(datagrams) = &self.datagrams {
+            writeln!(
+                w,
+                "    Lost/Total datagrams = {}/{} ({:.2}%), out of order = {}, jitter = {:.3}ms",
+                datagrams.lost,
+                datagrams.total,
+                datagrams.loss_pct,
+                datagrams.out_of_order,
+                datagrams.jitter_ms
+            )?;
+        }
+        if self.discarded_samples != 0 {
+            writeln!(
+                w,
+                "    Discarded samples = {} (timer went backwards)",
+                self.discarded_samples
+            )?;
+        }
+
+        if self.anchors.is_empty() && self.other.is_none() {
+            writeln!(w, "No blocks recorded")?;
+        } else {
+            self.root.write_text(w, units, color, precision)?;
+        }
+        for anchor in self.anchors.iter().chain(&self.other) {
+            anchor.write_text(w, units, color, precision)?;
+        }
+
+        Ok(())
+    }
+}
+
+// Renders text written through `fmt::Write`, which the core uses, into an
+// `io::Write`, keeping the I/O error that `fmt::Error` can't carry.
+#[cfg(feature = "std")]
+pub(crate) fn write_io<W: io::Write>(
+    w: W,
+    render: impl FnOnce(&mut IoWriter<W>) -> fmt::Result,
+) -> io::Result<()> {
+    let mut writer = IoWriter {
+        inner: w,
+        error: None,
+    };
+    render(&mut writer).map_err(|fmt::Error| {
+        writer
+            .error
+            .unwrap_or_else(|| io::Error::other("formatting the report failed"))
+    })
+}
+
+#[cfg(feature = "std")]
+pub(crate) struct IoWriter<W> {
+    inner: W,
+    error: Option<io::Error>,
+}
+
+#[cfg(feature = "std")]
+impl<W: io::Write> Write for IoWriter<W> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.inner.write_all(s.as_bytes()).map_err(|err| {
+            self.error = Some(err);
+            fmt::Error
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(feature = "std")]
+    use crate::tests::printed_with;
+    use crate::tests::{record, started};
+    #[cfg(all(feature = "std", feature = "profiling"))]
+    use crate::{tests::printed, timer::MockTimeSource, Profiler};
+
+    #[test]
+    fn durations_pick_a_readable_unit() {
+        let cases = [
+            (0.0, "0ns"),
+            (0.000012, "12.00ns"),
+            (0.000_000_5, "0.500ns"),
+            (0.0456, "45.60µs"),
+            (0.999, "999.0µs"),
+            (1.0, "1.000ms"),
+            (123.456, "123.5ms"),
+            (45_000.0, "45.00s"),
+            (7_200_000.0, "7200s"),
+        ];
+        for (ms, expected) in cases {
+            assert_eq!(ScaledMs(ms).to_string(), expected, "{ms}ms");
+        }
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn timestamps_print_as_rfc3339() {
+        let at = |secs, millis| {
+            let since_epoch =
+                std::time::Duration::from_secs(secs) + std::time::Duration::from_millis(millis);
+            Rfc3339(UNIX_EPOCH + since_epoch).to_string()
+        };
+        assert_eq!(at(0, 0), "1970-01-01T00:00:00.000Z");
+        assert_eq!(at(951_782_400, 5), "2000-02-29T00:00:00.005Z");
+        assert_eq!(at(1_700_000_000, 123), "2023-11-14T22:13:20.123Z");
+    }
+
+    #[cfg(all(feature = "std", feature = "profiling"))]
+    #[test]
+    fn report_math_with_mock_clock() {
+        let (clock, mut profiler) = started(1000);
+        record(&profiler, "outer", 150, 200, 1);
+        record(&profiler, "inner", 50, 50, 2);
+        clock.advance(300);
+
+        let output = printed(&mut profiler);
+        assert!(output.contains("CPU frequency: 1000hz"));
+        assert!(output.contains("Total time = 300.0ms"));
+        assert!(output.contains("outer[1]: 150.0ms (50.00%, 66.67% w/children, - of parent)"));
+        assert!(output.contains("inner[2]: 50.00ms (16.67%, - of parent)"));
+    }
+
+    #[cfg(all(feature = "std", feature = "profiling"))]
+    #[test]
+    fn throughput_in_bits_per_second() {
+        let (clock, mut profiler) = started(1000);
+        profiler.set_report_units(ReportUnits::Both);
+        // 125 MB in half a second is 2000 Mbit/s.
+        let index = record(&profiler, "send", 500, 500, 1);
+        profiler.anchors.borrow_mut()[index].bytes_processed = 125_000_000;
+        clock.advance(1000);
+
+        let output = printed(&mut profiler);
+        assert!(output.contains(" 119.209MBs at 0.23GB/s, 2000.00Mbits/sec (2.00Gbits/sec)"));
+    }
+
+    #[test]
+    fn report_for_a_two_block_run() {
+        let (clock, profiler) = started(1000);
+        record(&profiler, "parse", 300, 400, 2);
+        record(&profiler, "read", 100, 100, 4);
+        clock.advance(500);
+
+        let report = profiler.report();
+        assert_eq!(report.cpu_freq, 1000);
+        assert_eq!(report.total_ticks, 500);
+        assert_eq!(report.total_ms, 500.0);
+        assert_eq!(report.anchors.len(), 2);
+
+        let parse = &report.anchors[0];
+        assert_eq!(parse.label, "parse");
+        assert_eq!(parse.hits, 2);
+        assert_eq!((parse.exclusive_ms, parse.inclusive_ms), (300.0, 400.0));
+        assert_eq!((parse.percent, parse.percent_with_children), (60.0, 80.0));
+        assert!(parse.has_children());
+
+        let read = &report.anchors[1];
+        assert_eq!(read.label, "read");
+        assert_eq!(read.hits, 4);
+        assert_eq!(read.percent, 20.0);
+        assert!(!read.has_children());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn json_report_parses_back() {
+        let (clock, profiler) = started(1000);
+        record(&profiler, "parse", 300, 400, 2);
+        record(&profiler, "read", 100, 100, 4);
+        clock.advance(500);
+
+        let mut json = Vec::new();
+        profiler.write_json(&mut json).unwrap();
+        let value: serde_json::Value = serde_json::from_slice(&json).unwrap();
+        let labels: Vec<&str> = value["anchors"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|anchor| anchor["label"].as_str().unwrap())
+            .collect();
+        assert_eq!(labels, ["parse", "read"]);
+        assert_eq!(value["total_ticks"], 500);
+    }
+
+    #[cfg(all(feature = "std", feature = "profiling"))]
+    #[test]
+    fn hottest_anchor_is_printed_first() {
+        let (clock, mut profiler) = started(1000);
+        record(&profiler, "cold", 10, 10, 1);
+        record(&profiler, "hot", 300, 300, 1);
+        record(&profiler, "warm", 100, 100, 50);
+        clock.advance(500);
+
+        let output = printed(&mut profiler);
+        let first_anchor = output
+            .lines()
+            .find(|line| line.contains('[') && !line.starts_with(ROOT_LABEL))
+            .unwrap();
+        assert!(first_anchor.starts_with("hot[1]"), "{output}");
+
+        profiler.set_sort_by(SortBy::Hits);
+        let labels: Vec<String> = profiler
+            .report()
+            .anchors
+            .into_iter()
+            .map(|anchor| anchor.label)
+            .collect();
+        assert_eq!(labels, ["warm", "cold", "hot"]);
+    }
+
+    #[cfg(all(feature = "std", feature = "profiling"))]
+    #[test]
+    fn top_n_folds_the_rest_into_other() {
+        let (clock, mut profiler) = started(1000);
+        profiler.set_top_n(Some(3));
+        for i in 1..=10 {
+            record(&profiler, &format!("block {i}"), 10 * i, 10 * i, 1);
+        }
+        clock.advance(1000);
+
+        let output = printed(&mut profiler);
+        let anchors: Vec<&str> = output
+            .lines()
+            .filter(|line| line.contains('[') && !line.starts_with(ROOT_LABEL))
+            .collect();
+        assert_eq!(anchors.len(), 4, "{output}");
+        assert!(anchors[0].starts_with("block 10[1]"));
+        assert!(anchors[1].starts_with("block 9[1]"));
+        assert!(anchors[2].starts_with("block 8[1]"));
+        assert!(anchors[3].starts_with("(other)[7]: 280.0ms (28.00%, - of parent)"));
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn filtered_report_keeps_only_matching_labels() {
+        let (clock, mut profiler) = started(1000);
+        record(&profiler, "udp::send", 100, 100, 1);
+        record(&profiler, "tcp::send", 400, 400, 1);
+        record(&profiler, "udp::recv", 300, 300, 1);
+        clock.advance(1000);
+
+        let output = printed_with(&mut profiler, |profiler| {
+            profiler.print_filtered("udp::", PercentBase::Total)
+        });
+        let anchors: Vec<&str> = output
+            .lines()
+            .filter(|line| line.contains('[') && !line.starts_with(ROOT_LABEL))
+            .collect();
+        assert_eq!(
+            anchors,
+            [
+                "udp::recv[1]: 300.0ms (30.00%, - of parent) 1hits/s",
+                "udp::send[1]: 100.0ms (10.00%, - of parent) 1hits/s"
+            ]
+        );
+
+        let output = printed_with(&mut profiler, |profiler| {
+            profiler.print_filtered("udp::", PercentBase::Filtered)
+        });
+        assert!(output.contains("udp::recv[1]: 300.0ms (75.00%, - of parent)"));
+        assert!(output.contains("udp::send[1]: 100.0ms (25.00%, - of parent)"));
+        assert!(!output.contains("tcp::"));
+    }
+
+    #[cfg(all(feature = "std", feature = "profiling"))]
+    #[test]
+    fn results_render_into_a_buffer() {
+        let (clock, profiler) = started(1000);
+        profiler.profile("encode", || clock.advance(40));
+        clock.advance(60);
+
+        let mut output = Vec::new();
+        profiler.print_results_to(&mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        let mut lines = output.lines();
+        assert_eq!(lines.next(), Some("Performance report:"));
+        assert_eq!(lines.next(), Some("    CPU frequency: 1000hz"));
+        assert!(lines.next().unwrap().starts_with("    Started at "));
+        assert_eq!(lines.next(), Some("    Total time = 100.0ms"));
+        assert_eq!(
+            lines.next(),
+            Some("(root)[1]: 60.00ms (60.00%, 100.00% w/children, - of parent) 10hits/s")
+        );
+        assert_eq!(
+            lines.next(),
+            Some("encode[1]: 40.00ms (40.00%, - of parent) 10hits/s")
+        );
+    }
+
+    #[cfg(all(feature = "serde", feature = "fs"))]
+    #[test]
+    fn saved_reports_load_back_unchanged() {
+        let (clock, mut profiler) = started(3_000_000_000);
+        profiler.enable_histograms();
+        let send = record(&profiler, "send", 700, 900, 3);
+        let encode = record(&profiler, "encode", 200, 200, 3);
+        {
+            let mut anchors = profiler.anchors.borrow_mut();
+            anchors[send].bytes_processed = 12_345;
+            anchors[encode].parent_index = send;
+            anchors[encode].parents.push(crate::ParentEdge {
+                index: send,
+                hits: 3,
+                ticks: 600,
+            });
+            anchors[encode].min_tsc = Some(17);
+            anchors[encode].histogram = Some(crate::histogram::Histogram::default());
+            anchors[encode].histogram.as_mut().unwrap().record(64);
+        }
+        clock.advance(1000);
+        let report = profiler.report();
+
+        let path = std::env::temp_dir().join(format!("iperf-rs-{}-saved", std::process::id()));
+        report.save(&path).unwrap();
+        let loaded = ProfileReport::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded, report);
+        assert_eq!(loaded.anchors[1].parent.as_deref(), Some("send"));
+        assert!(loaded.anchors[1].p50_ms.is_some());
+    }
+
+    #[cfg(all(feature = "serde", feature = "fs"))]
+    #[test]
+    fn loading_an_unknown_version_fails() {
+        let path = std::env::temp_dir().join(format!("iperf-rs-{}-version", std::process::id()));
+        std::fs::write(&path, r#"{"version":999,"report":{}}"#).unwrap();
+        let err = ProfileReport::load(&path).unwrap_err();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[cfg(all(feature = "std", feature = "profiling"))]
+    #[test]
+    fn packet_size_and_rate_per_anchor() {
+        let (clock, mut profiler) = started(1000);
+        let send = record(&profiler, "send", 500, 500, 4000);
+        profiler.anchors.borrow_mut()[send].bytes_processed = 4000 * 1470;
+        clock.advance(2000);
+
+        let report = profiler.report();
+        let send = &report.anchors[0];
+        assert_eq!(send.bytes_per_hit, 1470.0);
+        assert_eq!(send.hits_per_second, 2000.0);
+
+        let output = printed(&mut profiler);
+        assert!(output.contains("send[4000]: 500.0ms (25.00%, - of parent) 2000hits/s"));
+        assert!(output.contains(", 1470B/hit, 0.00 cycles/B\n"));
+    }
+
+    #[cfg(all(feature = "std", feature = "profiling"))]
+    #[test]
+    fn hot_anchors_are_only_highlighted_on_a_terminal() {
+        let (clock, mut profiler) = started(1000);
+        record(&profiler, "hot", 300, 300, 1);
+        record(&profiler, "warm", 150, 150, 1);
+        record(&profiler, "cold", 10, 10, 1);
+        clock.advance(1000);
+
+        let output = printed(&mut profiler);
+        assert!(!output.contains('\x1b'), "{output}");
+
+        let mut colored = String::new();
+        profiler
+            .report()
+            .format_text_colored(&mut colored, ReportUnits::Bytes, true, None)
+            .unwrap();
+        let line = |label: &str| colored.lines().find(|line| line.contains(label)).unwrap();
+        assert!(line("hot[").starts_with("\x1b[31m"), "{colored}");
+        assert!(line("warm[").starts_with("\x1b[33m"), "{colored}");
+        assert!(!line("cold[").contains('\x1b'), "{colored}");
+        assert_eq!(
+            colored
+                .replace("\x1b[31m", "")
+                .replace("\x1b[33m", "")
+                .replace("\x1b[0m", ""),
+            output
+        );
+    }
+
+    #[cfg(all(feature = "std", feature = "profiling"))]
+    #[test]
+    fn header_shows_throughput_across_anchors() {
+        let (clock, mut profiler) = started(1000);
+        profiler.set_report_units(ReportUnits::Bits);
+        // 100 MB and 150 MB over two seconds is 1000 Mbit/s.
+        let send = record(&profiler, "send", 500, 500, 1);
+        let recv = record(&profiler, "recv", 500, 500, 1);
+        profiler.anchors.borrow_mut()[send].bytes_processed = 100_000_000;
+        profiler.anchors.borrow_mut()[recv].bytes_processed = 150_000_000;
+        clock.advance(2000);
+
+        let report = profiler.report();
+        assert_eq!(report.total_bytes(), 250_000_000);
+        assert_eq!(report.bytes_per_second(), 125_000_000.0);
+        let output = printed(&mut profiler);
+        assert!(
+            output.contains("    Throughput = 238.419MBs at 1000.00Mbits/sec (1.00Gbits/sec)\n"),
+            "{output}"
+        );
+    }
+
+    #[cfg(all(feature = "std", feature = "profiling"))]
+    #[test]
+    fn empty_and_instant_runs_print_finite_numbers() {
+        let (_, mut profiler) = started(1000);
+        let output = printed(&mut profiler);
+        assert!(
+            output.contains("Total time = 0ns\nNo blocks recorded\n"),
+            "{output}"
+        );
+
+        // A run whose clock never moved still has nothing to divide by.
+        profiler.start();
+        record(&profiler, "send", 50, 50, 0);
+        let report = profiler.report();
+        assert!(report.anchors.is_empty());
+        record(&profiler, "send", 50, 50, 2);
+        let anchor = &profiler.report().anchors[0];
+        assert_eq!((anchor.percent, anchor.hits_per_second), (0.0, 0.0));
+        let output = printed(&mut profiler);
+        assert!(
+            !output.contains("NaN") && !output.contains("inf"),
+            "{output}"
+        );
+    }
+
+    #[cfg(all(feature = "std", feature = "profiling"))]
+    #[test]
+    fn zero_length_run_prints_no_nan_or_inf() {
+        let (_, mut profiler) = started(1000);
+        let send = record(&profiler, "send", 50, 50, 4);
+        profiler.anchors.borrow_mut()[send].bytes_processed = 4096;
+        profiler.stop();
+        assert_eq!(profiler.report().total_ticks, 0);
+
+        let print_all = |profiler: &mut Profiler<MockTimeSource>| {
+            profiler.print_results();
+            profiler.print_tree();
+            profiler.print_filtered("send", PercentBase::Filtered);
+            profiler.print_filtered("recv", PercentBase::Filtered);
+        };
+        let output = printed_with(&mut profiler, print_all);
+        assert!(
+            !output.contains("NaN") && !output.contains("inf"),
+            "{output}"
+        );
+        assert_eq!(profiler.report().bytes_per_second(), 0.0);
+    }
+
+    #[cfg(feature = "profiling")]
+    #[test]
+    fn summary_line_names_the_hottest_anchor() {
+        let (clock, mut profiler) = started(1000);
+        let send = record(&profiler, "udp send", 600, 600, 1);
+        profiler.anchors.borrow_mut()[send].bytes_processed = 2 * 1024 * 1024;
+        record(&profiler, "recv", 200, 200, 1);
+        clock.advance(1000);
+        profiler.stop();
+
+        assert_eq!(
+            profiler.summary_line(),
+            "total_ms=1000.000 top_label=\"udp send\" top_pct=60.00 total_MBps=2.000"
+        );
+    }
+
+    #[cfg(all(feature = "std", feature = "profiling"))]
+    #[test]
+    fn precision_sets_the_printed_decimals() {
+        let (clock, mut profiler) = started(1_000_000);
+        profiler.set_precision(2);
+        let send = record(&profiler, "send", 12_345, 12_345, 1);
+        profiler.anchors.borrow_mut()[send].bytes_processed = 1024 * 1024;
+        clock.advance(250_000);
+
+        let output = printed(&mut profiler);
+        assert!(output.contains("    Total time = 250.00ms\n"), "{output}");
+        assert!(output.contains("send[1]: 12.35ms ("), "{output}");
+        assert!(output.contains(" 1.00MBs at 0.08GB/s"), "{output}");
+        assert_eq!(profiler.report().anchors[0].exclusive_ms, 12.345);
+    }
+
+    #[cfg(all(feature = "std", feature = "profiling"))]
+    #[test]
+    fn exclusive_times_and_the_root_add_up_to_the_total() {
+        let (clock, mut profiler) = started(1000);
+        clock.advance(15);
+        let outer = profiler.begin_block("outer");
+        clock.advance(30);
+        profiler.profile("inner", || clock.advance(45));
+        drop(outer);
+        clock.advance(10);
+
+        let report = profiler.report();
+        assert_eq!(
+            (report.root.exclusive_ticks, report.root.inclusive_ticks),
+            (25, 100)
+        );
+        let output = printed(&mut profiler);
+        let printed_ms: f64 = output
+            .lines()
+            .filter_map(|line| {
+                line.split_once("]: ")?
+                    .1
+                    .split_once("ms (")?
+                    .0
+                    .parse::<f64>()
+                    .ok()
+            })
+            .sum();
+        assert!(output.contains("(root)[1]: 25.00ms (25.00%"), "{output}");
+        assert_eq!(printed_ms, 100.0, "{output}");
+    }
+
+    #[cfg(all(feature = "std", feature = "profiling"))]
+    #[test]
+    fn cycles_per_byte_for_byte_bearing_anchors() {
+        let (clock, mut profiler) = started(1_000_000);
+        for _ in 0..4 {
+            let block = profiler.begin_block_with_bandwidth("copy", 4096);
+            clock.advance(10_240);
+            drop(block);
+        }
+        profiler.profile("idle", || clock.advance(100));
+
+        let report = profiler.report();
+        let cycles_per_byte = |label: &str| {
+            let anchor = report.anchors.iter().find(|anchor| anchor.label == label);
+            anchor.unwrap().cycles_per_byte
+        };
+        assert_eq!(cycles_per_byte("copy"), Some(2.5));
+        assert_eq!(cycles_per_byte("idle"), None);
+        let output = printed(&mut profiler);
+        assert!(output.contains(", 4096B/hit, 2.50 cycles/B"), "{output}");
+    }
+
+    #[cfg(feature = "profiling")]
+    #[test]
+    fn child_reports_its_share_of_the_parent() {
+        let (clock, mut profiler) = started(1000);
+        let outer = profiler.begin_block("outer");
+        clock.advance(150);
+        let inner = profiler.begin_block("inner");
+        clock.advance(50);
+        drop(inner);
+        drop(outer);
+        profiler.stop();
+
+        let report = profiler.report();
+        let percent_of_parent = |label: &str| {
+            let anchor = report.anchors.iter().find(|anchor| &*anchor.label == label);
+            anchor.unwrap().percent_of_parent
+        };
+        assert_eq!(percent_of_parent("outer"), None);
+        assert_eq!(percent_of_parent("inner"), Some(25.0));
+    }
+
+    #[cfg(feature = "profiling")]
+    #[test]
+    fn share_of_parent_counts_only_hits_under_it() {
+        let (clock, profiler) = started(1000);
+        profiler.profile("recv", || {
+            clock.advance(100);
+            profiler.profile("crc", || clock.advance(1000));
+        });
+        profiler.profile("send", || {
+            clock.advance(90);
+            profiler.profile("crc", || clock.advance(10));
+        });
+
+        let report = profiler.report();
+        let crc = report.anchors.iter().find(|anchor| anchor.label == "crc");
+        let crc = crc.unwrap();
+        assert_eq!(crc.parent.as_deref(), Some("send"));
+        assert_eq!(crc.percent_of_parent, Some(10.0));
+    }
+
+    #[cfg(all(feature = "std", feature = "profiling"))]
+    #[test]
+    fn call_sites_count_distinct_parents() {
+        let (clock, mut profiler) = started(1000);
+        for caller in ["request", "response", "request"] {
+            profiler.profile(caller, || {
+                clock.advance(5);
+                profiler.profile("checksum", || clock.advance(10));
+            });
+        }
+
+        let report = profiler.report();
+        let call_sites = |label: &str| {
+            let anchor = report.anchors.iter().find(|anchor| anchor.label == label);
+            anchor.unwrap().call_sites
+        };
+        assert_eq!(call_sites("checksum"), 2);
+        assert_eq!(call_sites("request"), 1);
+
+        let output = printed(&mut profiler);
+        let checksum = output
+            .lines()
+            .find(|line| line.starts_with("checksum"))
+            .unwrap();
+        assert!(checksum.contains(" called from 2 sites"), "{checksum}");
+    }
+
+    #[cfg(feature = "profiling")]
+    #[test]
+    fn call_sites_forget_callers_from_before_a_reset() {
+        let (clock, mut profiler) = started(1000);
+        profiler.profile("request", || {
+            profiler.profile("checksum", || clock.advance(10))
+        });
+        profiler.reset_anchor("checksum");
+        profiler.profile("response", || {
+            profiler.profile("checksum", || clock.advance(10))
+        });
+
+        let report = profiler.report();
+        let checksum = report
+            .anchors
+            .iter()
+            .find(|anchor| anchor.label == "checksum");
+        let checksum = checksum.unwrap();
+        assert_eq!(checksum.call_sites, 1);
+        assert_eq!(checksum.callers, ["response"]);
+    }
+}
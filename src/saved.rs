@@ -0,0 +1,221 @@
+//! Raw anchor counters written by one process and merged into another, e.g. by
+//! forked children handing their results back to the parent through a pipe.
+//!
+//! A forked child inherits the parent's profiler, including its calibrated tick
+//! rate, so the counters of both are in the same units. The child should `reset`
+//! its copy right after the fork so it doesn't send back what the parent had
+//! already recorded. Histograms aren't saved.
+
+use crate::{ParentEdge, ProfileAnchor, Profiler, TimeSource, ROOT_ANCHOR};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+
+// Bump whenever the layout of `SavedAnchors` changes.
+const SAVED_FORMAT_VERSION: u64 = 3;
+
+#[derive(Serialize, Deserialize)]
+struct SavedAnchors {
+    version: u64,
+    discarded_samples: u64,
+    // Indexed like the profiler's anchors, so parents can stay indices.
+    anchors: Vec<SavedAnchor>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SavedAnchor {
+    label: String,
+    exclusive_ticks: u64,
+    inclusive_ticks: u64,
+    hits: u64,
+    bytes: u64,
+    tx_bytes: u64,
+    rx_bytes: u64,
+    // None when no hit was timed.
+    min_ticks: Option<u64>,
+    max_ticks: u64,
+    last_ticks: u64,
+    over_budget: u64,
+    warmup_hits: u64,
+    parent_index: usize,
+    parents: Vec<SavedEdge>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SavedEdge {
+    index: usize,
+    hits: u64,
+    ticks: u64,
+}
+
+impl<T: TimeSource> Profiler<T> {
+    /// Writes the raw counters of every anchor to `w`, for `merge_saved` in
+    /// another process.
+    pub fn save_anchors<W: Write>(&self, w: W) -> io::Result<()> {
+        let saved = SavedAnchors {
+            version: SAVED_FORMAT_VERSION,
+            discarded_samples: self.discarded_samples.get(),
+            anchors: self
+                .anchors
+                .borrow()
+                .iter()
+                .map(|anchor| SavedAnchor {
+                    label: anchor.label.to_string(),
+                    exclusive_ticks: anchor.tsc_elapsed_exclusive,
+                    inclusive_ticks: anchor.tsc_elapsed_inclusive,
+                    hits: anchor.num_hits,
+                    bytes: anchor.bytes_processed,
+                    tx_bytes: anchor.tx_bytes,
+                    rx_bytes: anchor.rx_bytes,
+                    min_ticks: anchor.min_tsc,
+                    max_ticks: anchor.max_tsc,
+                    last_ticks: anchor.last_tsc,
+                    over_budget: anchor.over_budget,
+                    warmup_hits: anchor.warmup_hits,
+                    parent_index: anchor.parent_index,
+                    parents: anchor
+                        .parents
+                        .iter()
+                        .map(|edge| SavedEdge {
+                            index: edge.index,
+                            hits: edge.hits,
+                            ticks: edge.ticks,
+                        })
+                        .collect(),
+                })
+                .collect(),
+        };
+        serde_json::to_writer(w, &saved)?;
+        Ok(())
+    }
+
+    /// Reads counters written by `save_anchors` and adds them in like `merge`.
+    pub fn merge_saved<R: Read>(&mut self, r: R) -> io::Result<()> {
+        let saved: SavedAnchors = serde_json::from_reader(r)?;
+        if saved.version != SAVED_FORMAT_VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "unsupported saved anchors version {}, expected {SAVED_FORMAT_VERSION}",
+                    saved.version
+                ),
+            ));
+        }
+        let out_of_range = saved.anchors.iter().any(|anchor| {
+            std::iter::once(&anchor.parent_index)
+                .chain(anchor.parents.iter().map(|edge| &edge.index))
+                .any(|&parent| parent >= saved.anchors.len())
+        });
+        if out_of_range {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "saved anchor parent out of range",
+            ));
+        }
+
+        let discarded = self.discarded_samples.get() + saved.discarded_samples;
+        self.discarded_samples.set(discarded);
+
+        let index_map: Vec<(usize, usize)> = saved
+            .anchors
+            .iter()
+            .enumerate()
+            .skip(ROOT_ANCHOR + 1)
+            .filter(|(_, anchor)| anchor.hits != 0)
+            .filter_map(|(saved_index, anchor)| {
+                Some((saved_index, self.anchor_index(&anchor.label)?))
+            })
+            .collect();
+        let parent_map: HashMap<usize, usize> = index_map.iter().copied().collect();
+
+        let anchors = self.anchors.get_mut();
+        for (saved_index, index) in index_map {
+            let saved = &saved.anchors[saved_index];
+            let other = ProfileAnchor {
+                tsc_elapsed_exclusive: saved.exclusive_ticks,
+                tsc_elapsed_inclusive: saved.inclusive_ticks,
+                num_hits: saved.hits,
+                bytes_processed: saved.bytes,
+                tx_bytes: saved.tx_bytes,
+                rx_bytes: saved.rx_bytes,
+                min_tsc: saved.min_ticks,
+                max_tsc: saved.max_ticks,
+                last_tsc: saved.last_ticks,
+                over_budget: saved.over_budget,
+                budget_warned: false,
+                warmup_hits: saved.warmup_hits,
+                histogram: None,
+                parent_index: saved.parent_index,
+                parents: saved
+                    .parents
+                    .iter()
+                    .map(|edge| ParentEdge {
+                        index: edge.index,
+                        hits: edge.hits,
+                        ticks: edge.ticks,
+                    })
+                    .collect(),
+                sample_every: 0,
+                sample_phase: 0,
+                label: anchors[index].label.clone(),
+            };
+            anchors[index].merge(&other, &parent_map);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(test, unix, feature = "profiling"))]
+mod tests {
+    use super::*;
+    use crate::tests::started;
+    use crate::timer::MockTimeSource;
+    use nix::libc;
+    use std::fs::File;
+    use std::os::fd::FromRawFd;
+
+    #[test]
+    fn parent_merges_what_a_forked_child_recorded() {
+        let (clock, mut profiler) = started(1000);
+        profiler.profile("accept", || clock.advance(5));
+
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        let child = unsafe { libc::fork() };
+        assert!(child >= 0);
+        if child == 0 {
+            // Only report back through the exit status here; a panic would
+            // unwind into a copy of the test harness.
+            unsafe { libc::close(fds[0]) };
+            profiler.reset();
+            for _ in 0..3 {
+                profiler.profile("serve", || clock.advance(20));
+            }
+            let pipe = unsafe { File::from_raw_fd(fds[1]) };
+            let status = i32::from(profiler.save_anchors(pipe).is_err());
+            unsafe { libc::_exit(status) };
+        }
+
+        unsafe { libc::close(fds[1]) };
+        let merged = profiler.merge_saved(unsafe { File::from_raw_fd(fds[0]) });
+        let mut status = 0;
+        assert_eq!(unsafe { libc::waitpid(child, &mut status, 0) }, child);
+        assert!(libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0);
+        merged.unwrap();
+
+        let stats = |label| {
+            let anchor = profiler.anchor(label).unwrap();
+            (anchor.hits, anchor.inclusive_ticks)
+        };
+        assert_eq!(stats("accept"), (1, 5));
+        assert_eq!(stats("serve"), (3, 60));
+    }
+
+    #[test]
+    fn unknown_versions_are_rejected() {
+        let mut profiler = Profiler::with_time_source(MockTimeSource::new(1000));
+        let saved = r#"{"version":999,"discarded_samples":0,"anchors":[]}"#;
+        let err = profiler.merge_saved(saved.as_bytes()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
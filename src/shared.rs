@@ -0,0 +1,344 @@
+//! Aggregation of profiles recorded on several threads.
+//!
+//! A [`Profiler`] is cheap to borrow but can't be shared between threads. A
+//! [`SharedProfiler`] hands every worker its own [`ThreadProfiler`] to record
+//! into without any synchronization; when the worker drops it, its anchors are
+//! merged by label into the shared aggregate that `report()` reads from, and
+//! into a per-thread aggregate keyed by the thread's name that
+//! `report_by_thread()` reads from. Long-running workers can publish what they
+//! recorded so far with [`ThreadProfiler::flush`]. Thread profilers record with
+//! the shared profiler's settings, see [`SharedProfiler::configure`].
+
+use crate::{CpuTimeSource, ProfileOutput, ProfileReport, Profiler, TimeSource};
+use std::collections::BTreeMap;
+use std::io::stdout;
+use std::ops::Deref;
+use std::sync::{Mutex, MutexGuard};
+
+pub struct SharedProfiler<T: TimeSource = CpuTimeSource> {
+    aggregate: Mutex<Profiler<T>>,
+    by_thread: Mutex<BTreeMap<String, Profiler<T>>>,
+    time_source: T,
+}
+
+fn lock<U>(mutex: &Mutex<U>) -> MutexGuard<'_, U> {
+    mutex
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+// The thread's name, or its id for unnamed threads.
+fn current_thread_key() -> String {
+    let thread = std::thread::current();
+    match thread.name() {
+        Some(name) => name.to_string(),
+        None => format!("{:?}", thread.id()),
+    }
+}
+
+impl SharedProfiler {
+    pub fn new() -> Self {
+        Self::with_time_source(CpuTimeSource::default())
+    }
+}
+
+impl Default for SharedProfiler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: TimeSource + Clone> SharedProfiler<T> {
+    pub fn with_time_source(time_source: T) -> Self {
+        Self {
+            aggregate: Mutex::new(Profiler::with_time_source(time_source.clone())),
+            by_thread: Mutex::new(BTreeMap::new()),
+            time_source,
+        }
+    }
+
+    pub(crate) fn aggregate(&self) -> MutexGuard<'_, Profiler<T>> {
+        lock(&self.aggregate)
+    }
+
+    // A running profiler with the aggregate's settings and block overhead.
+    fn started_profiler(&self) -> Profiler<T> {
+        let mut profiler =
+            Profiler::with_output(self.time_source.clone(), ProfileOutput::Stdout(stdout()));
+        profiler.copy_settings(&self.aggregate());
+        profiler.reset();
+        profiler
+    }
+
+    /// Changes the settings of the shared profiler, such as warmup, sampling,
+    /// ignored labels or capacity. Thread profilers handed out afterwards
+    /// record with the same settings.
+    pub fn configure(&self, configure: impl FnOnce(&mut Profiler<T>)) {
+        configure(&mut self.aggregate());
+    }
+
+    pub fn start(&self) {
+        self.aggregate().start();
+        lock(&self.by_thread).clear();
+    }
+
+    pub fn thread_profiler(&self) -> ThreadProfiler<'_, T> {
+        let thread = current_thread_key();
+        lock(&self.by_thread)
+            .entry(thread.clone())
+            .or_insert_with(|| self.started_profiler());
+
+        ThreadProfiler {
+            profiler: self.started_profiler(),
+            thread,
+            shared: self,
+        }
+    }
+
+    /// Reports of what each thread recorded, keyed by thread name (or id if unnamed).
+    pub fn report_by_thread(&self) -> BTreeMap<String, ProfileReport> {
+        lock(&self.by_thread)
+            .iter()
+            .map(|(thread, profiler)| (thread.clone(), profiler.report()))
+            .collect()
+    }
+
+    pub fn report(&self) -> ProfileReport {
+        self.aggregate().report()
+    }
+
+    pub fn print_results(&self) {
+        self.aggregate().print_results();
+    }
+
+    pub fn end_and_print_results(&self) {
+        self.aggregate().end_and_print_results();
+    }
+}
+
+pub struct ThreadProfiler<'a, T: TimeSource + Clone = CpuTimeSource> {
+    profiler: Profiler<T>,
+    thread: String,
+    shared: &'a SharedProfiler<T>,
+}
+
+impl<T: TimeSource + Clone> Deref for ThreadProfiler<'_, T> {
+    type Target = Profiler<T>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.profiler
+    }
+}
+
+impl<T: TimeSource + Clone> ThreadProfiler<'_, T> {
+    /// Merges what this thread recorded since the last flush into the shared
+    /// profiler and zeroes it here, so reports and the live view include it
+    /// before the thread profiler is dropped. Workers can call it periodically,
+    /// e.g. once per batch; taking `&mut self` ensures no block is open.
+    pub fn flush(&mut self) {
+        self.publish();
+        self.profiler.reset_counts();
+    }
+
+    fn publish(&self) {
+        self.shared.aggregate().merge_anchors(&self.profiler);
+        // A `start` since this thread profiler was handed out cleared its entry.
+        lock(&self.shared.by_thread)
+            .entry(self.thread.clone())
+            .or_insert_with(|| self.shared.started_profiler())
+            .merge_anchors(&self.profiler);
+    }
+}
+
+impl<T: TimeSource + Clone> Drop for ThreadProfiler<'_, T> {
+    fn drop(&mut self) {
+        self.publish();
+    }
+}
+
+#[cfg(all(test, feature = "profiling"))]
+mod tests {
+    use super::*;
+    use crate::timer::MockTimeSource;
+
+    #[test]
+    fn threads_merge_by_label() {
+        let shared = SharedProfiler::with_time_source(MockTimeSource::new(1000));
+        shared.start();
+        std::thread::scope(|scope| {
+            for thread in 0..4 {
+                let shared = &shared;
+                scope.spawn(move || {
+                    let profiler = shared.thread_profiler();
+                    for _ in 0..10 {
+                        drop(profiler.begin_block_with_bandwidth("work", 8));
+                    }
+                    if thread == 0 {
+                        drop(profiler.begin_block("setup"));
+                    }
+                });
+            }
+        });
+
+        let aggregate = shared.aggregate();
+        let label_to_index = aggregate.label_to_index.borrow();
+        let anchors = aggregate.anchors.borrow();
+        let work = &anchors[label_to_index["work"]];
+        assert_eq!((work.num_hits, work.bytes_processed), (40, 320));
+        assert_eq!(anchors[label_to_index["setup"]].num_hits, 1);
+    }
+
+    #[test]
+    fn report_by_thread_breaks_labels_down_per_thread() {
+        let shared = SharedProfiler::with_time_source(MockTimeSource::ticking(1000, 1));
+        shared.start();
+        std::thread::scope(|scope| {
+            for (name, hits) in [("stream-1", 3), ("stream-2", 5)] {
+                let shared = &shared;
+                std::thread::Builder::new()
+                    .name(name.to_string())
+                    .spawn_scoped(scope, move || {
+                        let profiler = shared.thread_profiler();
+                        for _ in 0..hits {
+                            drop(profiler.begin_block("send"));
+                        }
+                    })
+                    .unwrap();
+            }
+        });
+
+        let by_thread = shared.report_by_thread();
+        let hits: Vec<(&str, u64)> = by_thread
+            .iter()
+            .map(|(thread, report)| (thread.as_str(), report.anchors.iter().map(|a| a.hits).sum()))
+            .collect();
+        assert_eq!(hits, [("stream-1", 3), ("stream-2", 5)]);
+    }
+
+    #[test]
+    fn flushed_hits_are_merged_once() {
+        let clock = MockTimeSource::new(1000);
+        let shared = SharedProfiler::with_time_source(clock.clone());
+        shared.start();
+        let mut profiler = shared.thread_profiler();
+        let record = |profiler: &ThreadProfiler<'_, MockTimeSource>, hits| {
+            for _ in 0..hits {
+                profiler.profile_with_bandwidth("send", 10, || clock.advance(5));
+            }
+        };
+        let hits = || {
+            shared
+                .report()
+                .anchors
+                .iter()
+                .map(|anchor| anchor.hits)
+                .sum::<u64>()
+        };
+
+        record(&profiler, 3);
+        assert_eq!(hits(), 0);
+        profiler.flush();
+        assert_eq!(hits(), 3);
+        assert_eq!(profiler.anchor("send").map(|send| send.hits), None);
+
+        record(&profiler, 2);
+        profiler.flush();
+        record(&profiler, 1);
+        drop(profiler);
+        let report = shared.report();
+        assert_eq!((report.anchors[0].hits, report.anchors[0].bytes), (6, 60));
+        assert_eq!(report.anchors[0].exclusive_ticks, 30);
+    }
+
+    #[test]
+    fn flushes_keep_the_callers_apart() {
+        let clock = MockTimeSource::new(1000);
+        let shared = SharedProfiler::with_time_source(clock.clone());
+        shared.start();
+        let mut profiler = shared.thread_profiler();
+        for caller in ["recv", "send"] {
+            profiler.profile(caller, || profiler.profile("crc", || clock.advance(5)));
+            profiler.flush();
+        }
+
+        let report = shared.report();
+        let crc = report.anchors.iter().find(|anchor| anchor.label == "crc");
+        let crc = crc.unwrap();
+        assert_eq!((crc.hits, crc.call_sites), (2, 2));
+        assert_eq!(crc.callers, ["recv", "send"]);
+    }
+    #[test]
+    fn thread_profilers_record_like_the_shared_one() {
+        let clock = MockTimeSource::new(1000);
+        let configure = |profiler: &mut Profiler<MockTimeSource>| {
+            profiler.set_warmup(1);
+            profiler.set_ignored(&["log"]);
+            profiler.set_sampling("crc", 2);
+        };
+        let run = |profiler: &Profiler<MockTimeSource>| {
+            for _ in 0..5 {
+                profiler.profile("crc", || clock.advance(10));
+                profiler.profile("log", || clock.advance(1));
+            }
+        };
+        let summary = |report: ProfileReport| -> Vec<(String, u64, u64, u64)> {
+            let anchors = report.anchors.into_iter();
+            anchors
+                .map(|anchor| {
+                    let ticks = anchor.exclusive_ticks;
+                    (anchor.label, anchor.hits, anchor.warmup_hits, ticks)
+                })
+                .collect()
+        };
+
+        let mut single = Profiler::with_time_source(clock.clone());
+        configure(&mut single);
+        single.start();
+        run(&single);
+
+        let shared = SharedProfiler::with_time_source(clock.clone());
+        shared.configure(configure);
+        shared.start();
+        run(&shared.thread_profiler());
+
+        let expected = summary(single.report());
+        assert_eq!(expected, [("crc".to_string(), 4, 1, 40)]);
+        assert_eq!(summary(shared.report()), expected);
+    }
+
+    #[test]
+    fn thread_profilers_reuse_the_measured_overhead() {
+        // Every clock read costs 7 ticks, so an empty block measures 7 ticks.
+        let clock = MockTimeSource::ticking(1_000_000, 7);
+        let shared = SharedProfiler::with_time_source(clock.clone());
+        shared.start();
+
+        let before = clock.now_ticks();
+        let profiler = shared.thread_profiler();
+        let reads = (clock.now_ticks() - before) / 7;
+        assert_eq!(profiler.block_overhead_ticks(), 7);
+        // Starting this thread's two profilers, not the thousand blocks of
+        // another overhead measurement.
+        assert_eq!(reads, 3);
+    }
+    #[test]
+    fn restarting_keeps_live_threads_in_the_breakdown() {
+        let clock = MockTimeSource::new(1000);
+        let shared = SharedProfiler::with_time_source(clock.clone());
+        shared.start();
+        std::thread::scope(|scope| {
+            std::thread::Builder::new()
+                .name("stream-1".to_string())
+                .spawn_scoped(scope, || {
+                    let profiler = shared.thread_profiler();
+                    shared.start();
+                    profiler.profile("send", || clock.advance(5));
+                })
+                .unwrap();
+        });
+
+        let total = shared.report().anchors[0].hits;
+        let by_thread = shared.report_by_thread();
+        assert_eq!(by_thread["stream-1"].anchors[0].hits, total);
+    }
+}
@@ -0,0 +1,180 @@
+use crate::report::ProfileReport;
+use crate::Map;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+/// Per-label timings of a run in milliseconds, so runs from different machines
+/// or processes can be compared with [`diff`]. Ticks are converted with the
+/// capturing profiler's own rate, so no raw ticks are kept.
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ProfileSnapshot {
+    pub total_ms: f64,
+    pub anchors: Vec<AnchorSnapshot>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct AnchorSnapshot {
+    pub label: String,
+    pub hits: u64,
+    pub exclusive_ms: f64,
+    pub inclusive_ms: f64,
+    pub bytes: u64,
+    pub bytes_per_second: f64,
+}
+
+impl From<&ProfileReport> for ProfileSnapshot {
+    fn from(report: &ProfileReport) -> Self {
+        Self {
+            total_ms: report.total_ms,
+            anchors: report
+                .anchors
+                .iter()
+                .chain(&report.other)
+                .map(|anchor| AnchorSnapshot {
+                    label: anchor.label.clone(),
+                    hits: anchor.hits,
+                    exclusive_ms: anchor.exclusive_ms,
+                    inclusive_ms: anchor.inclusive_ms,
+                    bytes: anchor.bytes,
+                    bytes_per_second: anchor.bytes_per_second,
+                })
+                .collect(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct AnchorDelta {
+    pub label: String,
+    pub before_ms: Option<f64>,
+    pub after_ms: Option<f64>,
+    /// Change in inclusive time; positive means `after` is slower.
+    /// Only set when the label is in both snapshots.
+    pub regression_percent: Option<f64>,
+    pub throughput_change_percent: Option<f64>,
+}
+
+fn percent_change(before: f64, after: f64) -> Option<f64> {
+    (before != 0.0 && before.is_finite() && after.is_finite())
+        .then(|| 100.0 * (after - before) / before)
+}
+
+/// Compares two snapshots label by label: every label of `after` in its order,
+/// then the labels that only `before` has.
+pub fn diff(before: &ProfileSnapshot, after: &ProfileSnapshot) -> Vec<AnchorDelta> {
+    let before_by_label: Map<&str, &AnchorSnapshot> = before
+        .anchors
+        .iter()
+        .map(|anchor| (anchor.label.as_str(), anchor))
+        .collect();
+    let after_by_label: Map<&str, &AnchorSnapshot> = after
+        .anchors
+        .iter()
+        .map(|anchor| (anchor.label.as_str(), anchor))
+        .collect();
+
+    let changed = after.anchors.iter().map(|anchor| {
+        let old = before_by_label.get(anchor.label.as_str());
+        AnchorDelta {
+            label: anchor.label.clone(),
+            before_ms: old.map(|old| old.inclusive_ms),
+            after_ms: Some(anchor.inclusive_ms),
+            regression_percent: old
+                .and_then(|old| percent_change(old.inclusive_ms, anchor.inclusive_ms)),
+            throughput_change_percent: old
+                .and_then(|old| percent_change(old.bytes_per_second, anchor.bytes_per_second)),
+        }
+    });
+    let removed = before
+        .anchors
+        .iter()
+        .filter(|anchor| !after_by_label.contains_key(anchor.label.as_str()))
+        .map(|anchor| AnchorDelta {
+            label: anchor.label.clone(),
+            before_ms: Some(anchor.inclusive_ms),
+            after_ms: None,
+            regression_percent: None,
+            throughput_change_percent: None,
+        });
+
+    changed.chain(removed).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn anchor(label: &str, inclusive_ms: f64, bytes_per_second: f64) -> AnchorSnapshot {
+        AnchorSnapshot {
+            label: label.to_string(),
+            hits: 1,
+            exclusive_ms: inclusive_ms,
+            inclusive_ms,
+            bytes: 0,
+            bytes_per_second,
+        }
+    }
+
+    #[test]
+    fn diff_reports_changes_by_label() {
+        let before = ProfileSnapshot {
+            total_ms: 100.0,
+            anchors: vec![
+                anchor("send", 40.0, 1000.0),
+                anchor("parse", 20.0, 0.0),
+                anchor("legacy", 5.0, 0.0),
+            ],
+        };
+        let after = ProfileSnapshot {
+            total_ms: 100.0,
+            anchors: vec![
+                anchor("parse", 10.0, 0.0),
+                anchor("send", 50.0, 800.0),
+                anchor("compress", 7.0, 0.0),
+            ],
+        };
+
+        let deltas = diff(&before, &after);
+        let labels: Vec<&str> = deltas.iter().map(|delta| delta.label.as_str()).collect();
+        assert_eq!(labels, ["parse", "send", "compress", "legacy"]);
+
+        assert_eq!(deltas[0].regression_percent, Some(-50.0));
+        assert_eq!(deltas[0].throughput_change_percent, None);
+        assert_eq!(deltas[1].regression_percent, Some(25.0));
+        assert_eq!(deltas[1].throughput_change_percent, Some(-20.0));
+        assert_eq!((deltas[2].before_ms, deltas[2].after_ms), (None, Some(7.0)));
+        assert_eq!((deltas[3].before_ms, deltas[3].after_ms), (Some(5.0), None));
+        assert_eq!(deltas[3].regression_percent, None);
+    }
+
+    #[cfg(feature = "profiling")]
+    #[test]
+    fn snapshots_from_different_tick_rates_compare_equal() {
+        use crate::timer::MockTimeSource;
+        use crate::Profiler;
+
+        let snapshot_at = |ticks_per_second: u64| {
+            let clock = MockTimeSource::new(ticks_per_second);
+            let mut profiler = Profiler::with_time_source(clock.clone());
+            profiler.start();
+            // 25ms of sending in a 100ms run, whatever the rate.
+            let block = profiler.begin_block_with_bandwidth("send", 1000);
+            clock.advance(ticks_per_second / 40);
+            drop(block);
+            clock.advance(3 * ticks_per_second / 40);
+            profiler.stop();
+            assert_eq!(profiler.to_nanos(ticks_per_second / 40), 25_000_000);
+            profiler.snapshot()
+        };
+
+        let slow = snapshot_at(1_000_000);
+        let fast = snapshot_at(3_000_000_000);
+        assert_eq!(slow, fast);
+        assert_eq!(slow.anchors[0].inclusive_ms, 25.0);
+        assert!(diff(&slow, &fast)
+            .iter()
+            .all(|delta| delta.regression_percent == Some(0.0)));
+    }
+}
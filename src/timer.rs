@@ -0,0 +1,290 @@
+//! Timer backends used by the profiler.
+//!
+//! On x86_64 and aarch64 unix targets the profiler reads the hardware counter
+//! (`rdtsc` / `cntvct_el0`) directly. Every other target, or any build with the
+//! `portable-timer` feature enabled, falls back to `std::time::Instant`. The
+//! fallback keeps the crate usable on Windows and WASM, but each read costs a
+//! syscall-backed clock query, so very small blocks lose precision.
+//!
+//! Both need std; without it only the [`TimeSource`] trait is left, for the
+//! caller to implement over whatever counter the target has.
+
+#[cfg(all(
+    feature = "std",
+    unix,
+    any(target_arch = "x86_64", target_arch = "aarch64"),
+    not(feature = "portable-timer")
+))]
+mod hardware;
+#[cfg(all(
+    feature = "std",
+    unix,
+    any(target_arch = "x86_64", target_arch = "aarch64"),
+    not(feature = "portable-timer")
+))]
+use hardware::{get_cpu_frequency, has_invariant_tsc, read_cpu_timer, recalibrate_cpu_frequency};
+
+#[cfg(feature = "std")]
+mod portable;
+
+#[cfg(all(
+    feature = "std",
+    not(all(
+        unix,
+        any(target_arch = "x86_64", target_arch = "aarch64"),
+        not(feature = "portable-timer")
+    ))
+))]
+use portable::{get_cpu_frequency, has_invariant_tsc, read_cpu_timer, recalibrate_cpu_frequency};
+
+#[cfg(feature = "std")]
+use std::time::Duration;
+
+/// A monotonic tick counter together with its rate, used for all profiler timing.
+pub trait TimeSource {
+    fn now_ticks(&self) -> u64;
+    fn ticks_per_second(&self) -> u64;
+
+    /// Whether ticks advance at a constant rate regardless of power states.
+    fn is_invariant(&self) -> bool {
+        true
+    }
+
+    /// Discards any cached rate so the next `ticks_per_second` is measured afresh.
+    fn recalibrate(&self) {}
+}
+
+#[cfg(feature = "std")]
+pub const DEFAULT_CALIBRATION: Duration = Duration::from_millis(100);
+
+#[cfg(feature = "std")]
+/// The OS clock the hardware counter is calibrated against.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CalibrationClock {
+    /// `gettimeofday`, microsecond resolution.
+    #[default]
+    WallClock,
+    /// `clock_gettime(CLOCK_MONOTONIC)`, nanosecond resolution and immune to
+    /// wall-clock adjustments. Falls back to `WallClock` where unavailable.
+    Monotonic,
+}
+
+#[cfg(feature = "std")]
+/// Reads the counter behind [`CpuTimeSource`]: TSC cycles on x86_64, `cntvct_el0`
+/// ticks on aarch64 and nanoseconds with `portable-timer`.
+///
+/// ```
+/// let start = iperf_rs::cpu_timer();
+/// std::thread::sleep(std::time::Duration::from_millis(5));
+/// let ticks = iperf_rs::cpu_timer() - start;
+///
+/// let seconds = ticks as f64 / iperf_rs::estimate_cpu_hz() as f64;
+/// assert!(seconds >= 0.004);
+/// ```
+#[inline]
+pub fn cpu_timer() -> u64 {
+    read_cpu_timer()
+}
+
+#[cfg(feature = "std")]
+/// Ticks per second of [`cpu_timer`], measured once over [`DEFAULT_CALIBRATION`] and cached.
+pub fn estimate_cpu_hz() -> u64 {
+    get_cpu_frequency(DEFAULT_CALIBRATION, CalibrationClock::default())
+}
+
+#[cfg(feature = "std")]
+/// The hardware cycle counter, or `Instant` when built with `portable-timer`.
+///
+/// The counter rate is measured by busy-waiting for the calibration window and
+/// cached per process for each window and clock, so sources set up alike share
+/// one measurement; `recalibrate` measures it again. The window is split into
+/// several shorter ones and the median rate is kept. On Linux the thread is
+/// pinned to its current core while calibrating; if the affinity can't be set
+/// it calibrates unpinned and relies on the median alone.
+#[derive(Clone, Copy, Debug)]
+pub struct CpuTimeSource {
+    calibration: Duration,
+    clock: CalibrationClock,
+}
+
+#[cfg(feature = "std")]
+impl CpuTimeSource {
+    pub fn with_calibration(calibration: Duration) -> Self {
+        Self {
+            calibration,
+            clock: CalibrationClock::default(),
+        }
+    }
+
+    pub fn with_clock(mut self, clock: CalibrationClock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub fn calibration(&self) -> Duration {
+        self.calibration
+    }
+
+    pub fn clock(&self) -> CalibrationClock {
+        self.clock
+    }
+}
+
+#[cfg(feature = "std")]
+impl Default for CpuTimeSource {
+    fn default() -> Self {
+        Self::with_calibration(DEFAULT_CALIBRATION)
+    }
+}
+
+#[cfg(feature = "std")]
+impl TimeSource for CpuTimeSource {
+    #[inline]
+    fn now_ticks(&self) -> u64 {
+        cpu_timer()
+    }
+
+    #[inline]
+    fn ticks_per_second(&self) -> u64 {
+        get_cpu_frequency(self.calibration, self.clock)
+    }
+
+    fn is_invariant(&self) -> bool {
+        has_invariant_tsc()
+    }
+
+    fn recalibrate(&self) {
+        recalibrate_cpu_frequency(self.calibration, self.clock);
+    }
+}
+
+#[cfg(feature = "std")]
+/// Nanosecond ticks from `std::time::Instant`, available on every target.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct InstantTimeSource;
+
+#[cfg(feature = "std")]
+impl TimeSource for InstantTimeSource {
+    #[inline]
+    fn now_ticks(&self) -> u64 {
+        portable::read_cpu_timer()
+    }
+
+    #[inline]
+    fn ticks_per_second(&self) -> u64 {
+        portable::get_cpu_frequency(DEFAULT_CALIBRATION, CalibrationClock::default())
+    }
+
+    fn is_invariant(&self) -> bool {
+        portable::has_invariant_tsc()
+    }
+
+    fn recalibrate(&self) {
+        portable::recalibrate_cpu_frequency(DEFAULT_CALIBRATION, CalibrationClock::default());
+    }
+}
+
+/// A clock for tests that only moves when told to. Clones share the same time,
+/// so a test can keep one and hand the other to a profiler. Like a real counter
+/// it starts far from zero. A `ticking` clock also moves by a fixed step on
+/// every read, as if each read cost that much.
+#[cfg(test)]
+#[derive(Clone, Debug)]
+pub(crate) struct MockTimeSource {
+    now: std::sync::Arc<std::sync::atomic::AtomicU64>,
+    ticks_per_second: u64,
+    step: u64,
+}
+
+#[cfg(test)]
+impl MockTimeSource {
+    pub(crate) fn new(ticks_per_second: u64) -> Self {
+        Self::ticking(ticks_per_second, 0)
+    }
+
+    pub(crate) fn ticking(ticks_per_second: u64, step: u64) -> Self {
+        Self {
+            now: std::sync::Arc::new(std::sync::atomic::AtomicU64::new(1_000_000)),
+            ticks_per_second,
+            step,
+        }
+    }
+
+    pub(crate) fn set(&self, ticks: u64) {
+        self.now.store(ticks, std::sync::atomic::Ordering::Relaxed);
+    }
+
+    pub(crate) fn advance(&self, ticks: u64) {
+        self.now
+            .fetch_add(ticks, std::sync::atomic::Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+impl TimeSource for MockTimeSource {
+    fn now_ticks(&self) -> u64 {
+        self.now
+            .fetch_add(self.step, std::sync::atomic::Ordering::Relaxed)
+    }
+
+    fn ticks_per_second(&self) -> u64 {
+        self.ticks_per_second
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn cpu_timer_advances() {
+        let first = read_cpu_timer();
+        let mut second = read_cpu_timer();
+        while second == first {
+            std::hint::spin_loop();
+            second = read_cpu_timer();
+        }
+        assert!(second > first);
+    }
+
+    #[test]
+    fn mock_time_source_moves_only_when_told() {
+        let clock = MockTimeSource::new(1000);
+        let shared = clock.clone();
+        assert_eq!(shared.now_ticks(), 1_000_000);
+
+        clock.set(40);
+        clock.advance(2);
+        assert_eq!(shared.now_ticks(), 42);
+        assert_eq!(shared.now_ticks(), 42);
+        assert_eq!(shared.ticks_per_second(), 1000);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn invariant_tsc_check_runs() {
+        let invariant = CpuTimeSource::default().is_invariant();
+        assert_eq!(CpuTimeSource::default().is_invariant(), invariant);
+        assert!(InstantTimeSource.is_invariant());
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn back_to_back_reads_cost_a_stable_amount() {
+        // The cheapest of many read pairs is the timer's own overhead; it should
+        // come out about the same every round.
+        let cheapest_pair = || {
+            (0..10_000)
+                .map(|_| {
+                    let start = read_cpu_timer();
+                    read_cpu_timer() - start
+                })
+                .min()
+                .unwrap()
+        };
+        let rounds: Vec<u64> = (0..5).map(|_| cheapest_pair()).collect();
+        let (min, max) = (rounds.iter().min().unwrap(), rounds.iter().max().unwrap());
+        assert!(*max <= min * 4 + 100, "{rounds:?}");
+    }
+}
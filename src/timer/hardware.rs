@@ -0,0 +1,372 @@
+#[cfg(target_arch = "x86_64")]
+use std::arch::x86_64::__cpuid;
+#[cfg(all(target_arch = "x86_64", not(feature = "serialized-tsc")))]
+use std::arch::x86_64::_rdtsc;
+#[cfg(all(target_arch = "x86_64", feature = "serialized-tsc"))]
+use std::arch::x86_64::{__rdtscp, _mm_lfence};
+use std::ptr::null_mut;
+use std::sync::{Mutex, MutexGuard};
+use std::time::Duration;
+
+use super::CalibrationClock;
+
+#[cfg(all(target_arch = "x86_64", not(feature = "serialized-tsc")))]
+#[inline]
+pub fn read_cpu_timer() -> u64 {
+    unsafe { _rdtsc() }
+}
+
+#[cfg(all(target_arch = "x86_64", feature = "serialized-tsc"))]
+#[inline]
+pub fn read_cpu_timer() -> u64 {
+    let mut aux = 0u32;
+    unsafe {
+        _mm_lfence();
+        let value = __rdtscp(&mut aux);
+        _mm_lfence();
+        value
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+#[inline]
+pub fn read_cpu_timer() -> u64 {
+    let value: u64;
+    unsafe { std::arch::asm!("mrs {}, cntvct_el0", out(reg) value, options(nomem, nostack)) };
+    value
+}
+
+#[cfg(target_arch = "aarch64")]
+#[inline]
+fn read_cpu_timer_frequency() -> u64 {
+    let value: u64;
+    unsafe { std::arch::asm!("mrs {}, cntfrq_el0", out(reg) value, options(nomem, nostack)) };
+    value
+}
+
+#[cfg(target_arch = "x86_64")]
+pub fn has_invariant_tsc() -> bool {
+    const ADVANCED_POWER_MANAGEMENT_LEAF: u32 = 0x8000_0007;
+    const INVARIANT_TSC_BIT: u32 = 1 << 8;
+
+    let max_extended_leaf = __cpuid(0x8000_0000).eax;
+    if max_extended_leaf < ADVANCED_POWER_MANAGEMENT_LEAF {
+        return false;
+    }
+
+    let power_management = __cpuid(ADVANCED_POWER_MANAGEMENT_LEAF);
+    power_management.edx & INVARIANT_TSC_BIT != 0
+}
+
+#[cfg(target_arch = "aarch64")]
+pub fn has_invariant_tsc() -> bool {
+    true
+}
+
+const MICROS_PER_SECOND: u64 = 1_000_000;
+
+// Ticks per second of `read_os_timer`, which counts microseconds.
+#[inline]
+fn get_os_clock_frequency() -> u64 {
+    MICROS_PER_SECOND
+}
+
+// Microseconds since the epoch, as reported by gettimeofday.
+#[inline]
+fn read_os_timer() -> u64 {
+    let mut value = nix::libc::timeval {
+        tv_sec: 0,
+        tv_usec: 0,
+    };
+
+    let call_res = unsafe { nix::libc::gettimeofday(&mut value, null_mut()) };
+    assert_ne!(call_res, -1, "gettimeofday() failed");
+    (value.tv_sec as u64)
+        .saturating_mul(MICROS_PER_SECOND)
+        .saturating_add(value.tv_usec as u64)
+}
+
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+
+// Nanoseconds of CLOCK_MONOTONIC, or None where the clock isn't available.
+#[inline]
+fn read_monotonic_timer() -> Option<u64> {
+    let mut value = nix::libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+
+    let call_res = unsafe { nix::libc::clock_gettime(nix::libc::CLOCK_MONOTONIC, &mut value) };
+    (call_res != -1).then(|| {
+        (value.tv_sec as u64)
+            .saturating_mul(NANOS_PER_SECOND)
+            .saturating_add(value.tv_nsec as u64)
+    })
+}
+
+// Rates measured so far, one per calibration window and clock, so time sources
+// set up differently don't share one estimate. Few settings are ever in use, so
+// a list is searched instead of hashed.
+static CPU_FREQUENCIES: Mutex<Vec<(Duration, CalibrationClock, u64)>> = Mutex::new(Vec::new());
+
+fn cpu_frequencies() -> MutexGuard<'static, Vec<(Duration, CalibrationClock, u64)>> {
+    CPU_FREQUENCIES
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+#[inline]
+pub fn get_cpu_frequency(calibration: Duration, clock: CalibrationClock) -> u64 {
+    let cached = cpu_frequencies()
+        .iter()
+        .find(|&&(window, cached_clock, _)| window == calibration && cached_clock == clock)
+        .map(|&(_, _, freq)| freq);
+    cached.unwrap_or_else(|| recalibrate_cpu_frequency(calibration, clock))
+}
+
+pub fn recalibrate_cpu_frequency(calibration: Duration, clock: CalibrationClock) -> u64 {
+    // Measured before taking the lock, which would otherwise be held for the
+    // whole busy-wait.
+    let freq = measure_cpu_frequency(calibration, clock);
+    let mut frequencies = cpu_frequencies();
+    match frequencies
+        .iter_mut()
+        .find(|(window, cached_clock, _)| *window == calibration && *cached_clock == clock)
+    {
+        Some(entry) => entry.2 = freq,
+        None => frequencies.push((calibration, clock, freq)),
+    }
+    freq
+}
+
+#[cfg(target_arch = "x86_64")]
+#[inline]
+fn measure_cpu_frequency(calibration: Duration, clock: CalibrationClock) -> u64 {
+    calibrate_cpu_frequency(calibration, clock)
+}
+
+#[cfg(target_arch = "aarch64")]
+#[inline]
+fn measure_cpu_frequency(calibration: Duration, clock: CalibrationClock) -> u64 {
+    match read_cpu_timer_frequency() {
+        0 => calibrate_cpu_frequency(calibration, clock),
+        freq => freq,
+    }
+}
+
+// Keeps the calling thread on the core it was running on until dropped, then
+// restores its previous affinity. Does nothing if the affinity can't be read
+// or set, e.g. inside a restricted cgroup or off Linux.
+struct PinnedToCore {
+    #[cfg(target_os = "linux")]
+    previous: Option<nix::libc::cpu_set_t>,
+}
+
+impl PinnedToCore {
+    #[cfg(target_os = "linux")]
+    fn current() -> Self {
+        use nix::libc::{cpu_set_t, sched_getaffinity, sched_getcpu, sched_setaffinity, CPU_SET};
+
+        let size = std::mem::size_of::<cpu_set_t>();
+        let previous = unsafe {
+            let mut previous: cpu_set_t = std::mem::zeroed();
+            let cpu = sched_getcpu();
+            if cpu < 0 || sched_getaffinity(0, size, &mut previous) == -1 {
+                None
+            } else {
+                let mut pinned: cpu_set_t = std::mem::zeroed();
+                CPU_SET(cpu as usize, &mut pinned);
+                (sched_setaffinity(0, size, &pinned) != -1).then_some(previous)
+            }
+        };
+        Self { previous }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn current() -> Self {
+        Self {}
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Drop for PinnedToCore {
+    fn drop(&mut self) {
+        if let Some(previous) = &self.previous {
+            let size = std::mem::size_of::<nix::libc::cpu_set_t>();
+            unsafe { nix::libc::sched_setaffinity(0, size, previous) };
+        }
+    }
+}
+
+// Number of windows the calibration period is split into. The median of
+// their rates is used, so one window skewed by throttling or a migration
+// doesn't move the estimate.
+const CALIBRATION_WINDOWS: u32 = 5;
+
+#[inline]
+fn calibrate_cpu_frequency(calibration: Duration, clock: CalibrationClock) -> u64 {
+    let _pinned = PinnedToCore::current();
+    let window = calibration / CALIBRATION_WINDOWS;
+    let mut rates: Vec<u64> = (0..CALIBRATION_WINDOWS)
+        .map(|_| calibrate_window(window, clock))
+        .collect();
+    rates.sort_unstable();
+    rates[rates.len() / 2]
+}
+
+#[inline]
+fn calibrate_window(calibration: Duration, clock: CalibrationClock) -> u64 {
+    let (read_os_clock, os_freq): (fn() -> u64, u64) = match clock {
+        CalibrationClock::Monotonic if read_monotonic_timer().is_some() => (
+            || read_monotonic_timer().unwrap_or_default(),
+            NANOS_PER_SECOND,
+        ),
+        _ => (read_os_timer, get_os_clock_frequency()),
+    };
+
+    let cpu_start = read_cpu_timer();
+    let os_start = read_os_clock();
+    let mut os_elapsed = 0u64;
+    let os_wait_time = (os_freq as u128 * calibration.as_nanos() / 1_000_000_000).max(1) as u64;
+
+    while os_elapsed < os_wait_time {
+        os_elapsed = read_os_clock().saturating_sub(os_start);
+    }
+
+    let cpu_end = read_cpu_timer();
+    let cpu_elapsed = cpu_end - cpu_start;
+
+    assert_ne!(os_elapsed, 0, "os elapsed is zero!");
+    (os_freq as u128 * cpu_elapsed as u128 / os_elapsed as u128) as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn within_percent(a: u64, b: u64, percent: f64) -> bool {
+        (a as f64 - b as f64).abs() <= b as f64 * percent / 100.0
+    }
+
+    #[test]
+    fn short_and_long_calibration_windows_agree() {
+        let short = calibrate_cpu_frequency(Duration::from_millis(10), CalibrationClock::WallClock);
+        let long = calibrate_cpu_frequency(Duration::from_millis(200), CalibrationClock::WallClock);
+        assert!(within_percent(short, long, 3.0), "{short} vs {long}");
+    }
+
+    #[test]
+    fn sub_millisecond_windows_still_measure() {
+        let long = calibrate_cpu_frequency(Duration::from_millis(100), CalibrationClock::WallClock);
+        assert!(calibrate_cpu_frequency(Duration::from_nanos(1), CalibrationClock::WallClock) > 0);
+
+        let short =
+            calibrate_cpu_frequency(Duration::from_micros(500), CalibrationClock::WallClock);
+        assert!(within_percent(short, long, 25.0), "{short} vs {long}");
+    }
+
+    #[test]
+    fn os_timer_counts_microseconds() {
+        let mut previous = read_os_timer();
+        for _ in 0..1000 {
+            let now = read_os_timer();
+            assert!(now >= previous);
+            previous = now;
+        }
+
+        let start = read_os_timer();
+        std::thread::sleep(Duration::from_millis(10));
+        let elapsed = read_os_timer() - start;
+        assert!((10_000..200_000).contains(&elapsed), "{elapsed}");
+    }
+
+    #[test]
+    fn calibration_matches_a_monotonic_clock() {
+        assert_eq!(get_os_clock_frequency(), MICROS_PER_SECOND);
+
+        // std's Instant reads CLOCK_MONOTONIC, independent of gettimeofday.
+        let start = std::time::Instant::now();
+        let cpu_start = read_cpu_timer();
+        while start.elapsed() < Duration::from_millis(100) {
+            std::hint::spin_loop();
+        }
+        let cpu_elapsed = read_cpu_timer() - cpu_start;
+        let reference = (cpu_elapsed as f64 / start.elapsed().as_secs_f64()) as u64;
+
+        let calibrated =
+            calibrate_cpu_frequency(Duration::from_millis(100), CalibrationClock::WallClock);
+        assert!(
+            within_percent(calibrated, reference, 3.0),
+            "{calibrated} vs {reference}"
+        );
+    }
+
+    #[test]
+    fn monotonic_calibration_is_as_stable_as_wall_clock() {
+        let spread = |clock| {
+            let mut runs: Vec<u64> = (0..5)
+                .map(|_| calibrate_cpu_frequency(Duration::from_millis(10), clock))
+                .collect();
+            runs.sort_unstable();
+            (runs[2], runs[4] - runs[0])
+        };
+        let (wall_median, wall_spread) = spread(CalibrationClock::WallClock);
+        let (monotonic_median, monotonic_spread) = spread(CalibrationClock::Monotonic);
+
+        assert!(read_monotonic_timer().is_some());
+        assert!(within_percent(monotonic_median, wall_median, 3.0));
+        // Nanosecond reads shouldn't add jitter over microsecond ones; allow
+        // some scheduling noise on top.
+        assert!(
+            monotonic_spread <= wall_spread + wall_median / 100,
+            "{monotonic_spread} vs {wall_spread}"
+        );
+    }
+
+    #[test]
+    fn repeated_calibrations_agree() {
+        let runs: Vec<u64> = (0..5)
+            .map(|_| {
+                calibrate_cpu_frequency(Duration::from_millis(50), CalibrationClock::WallClock)
+            })
+            .collect();
+        let (min, max) = (*runs.iter().min().unwrap(), *runs.iter().max().unwrap());
+        assert!(within_percent(min, max, 2.0), "{runs:?}");
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn calibration_restores_the_thread_affinity() {
+        use nix::libc::{cpu_set_t, sched_getaffinity, CPU_EQUAL};
+
+        let affinity = || unsafe {
+            let mut set: cpu_set_t = std::mem::zeroed();
+            sched_getaffinity(0, std::mem::size_of::<cpu_set_t>(), &mut set);
+            set
+        };
+        let before = affinity();
+        calibrate_cpu_frequency(Duration::from_millis(5), CalibrationClock::WallClock);
+        assert!(unsafe { CPU_EQUAL(&before, &affinity()) });
+    }
+
+    #[test]
+    fn rates_are_cached_per_window_and_clock() {
+        let cached = |calibration, clock| {
+            cpu_frequencies()
+                .iter()
+                .find(|&&(window, cached_clock, _)| window == calibration && cached_clock == clock)
+                .map(|&(_, _, freq)| freq)
+        };
+        let (wall, monotonic) = (CalibrationClock::WallClock, CalibrationClock::Monotonic);
+        let window = Duration::from_micros(2500);
+
+        let wall_freq = get_cpu_frequency(window, wall);
+        assert_eq!(cached(window, wall), Some(wall_freq));
+        assert_eq!(cached(window, monotonic), None);
+
+        let monotonic_freq = get_cpu_frequency(window, monotonic);
+        assert_eq!(cached(window, monotonic), Some(monotonic_freq));
+        assert_eq!(cached(Duration::from_micros(2600), wall), None);
+        assert_eq!(get_cpu_frequency(window, wall), wall_freq);
+    }
+}
@@ -0,0 +1,46 @@
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+
+use super::CalibrationClock;
+
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+
+fn epoch() -> Instant {
+    static EPOCH: OnceLock<Instant> = OnceLock::new();
+    *EPOCH.get_or_init(Instant::now)
+}
+
+#[inline]
+pub fn read_cpu_timer() -> u64 {
+    epoch().elapsed().as_nanos() as u64
+}
+
+#[inline]
+pub fn get_cpu_frequency(_calibration: Duration, _clock: CalibrationClock) -> u64 {
+    NANOS_PER_SECOND
+}
+
+pub fn recalibrate_cpu_frequency(_calibration: Duration, _clock: CalibrationClock) -> u64 {
+    NANOS_PER_SECOND
+}
+
+pub fn has_invariant_tsc() -> bool {
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_nanoseconds_since_first_read() {
+        assert_eq!(
+            get_cpu_frequency(Duration::ZERO, CalibrationClock::Monotonic),
+            NANOS_PER_SECOND
+        );
+
+        let start = read_cpu_timer();
+        std::thread::sleep(std::time::Duration::from_millis(2));
+        assert!(read_cpu_timer() - start >= 2_000_000);
+    }
+}
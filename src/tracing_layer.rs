@@ -0,0 +1,127 @@
+//! Records `tracing` spans as blocks of the per-thread [`global`](crate::global) profiler.
+//!
+//! Entering a span opens a block labelled with the span's name and exiting it
+//! closes the block. A `bytes` field on the span is counted as bandwidth. Like
+//! any other block, a span's parent is whichever block was open on the thread
+//! when it was entered, which for ordinary nested spans is the enclosing span.
+
+use crate::global;
+use std::cell::RefCell;
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::Subscriber;
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Layer;
+
+const BYTES_FIELD: &str = "bytes";
+
+thread_local! {
+    static OPEN_SPANS: RefCell<Vec<(Id, global::Block)>> = const { RefCell::new(Vec::new()) };
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ProfilerLayer;
+
+impl ProfilerLayer {
+    pub fn new() -> Self {
+        Self
+    }
+}
+
+struct SpanBytes(u64);
+
+impl Visit for SpanBytes {
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        if field.name() == BYTES_FIELD {
+            self.0 = value;
+        }
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.record_u64(field, value.max(0) as u64);
+    }
+
+    fn record_debug(&mut self, _field: &Field, _value: &dyn std::fmt::Debug) {}
+}
+
+impl<S> Layer<S> for ProfilerLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let mut bytes = SpanBytes(0);
+        attrs.record(&mut bytes);
+        if let Some(span) = ctx.span(id) {
+            span.extensions_mut().insert(bytes);
+        }
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            if let Some(bytes) = span.extensions_mut().get_mut::<SpanBytes>() {
+                values.record(bytes);
+            }
+        }
+    }
+
+    fn on_enter(&self, id: &Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+
+        let bytes = span
+            .extensions()
+            .get::<SpanBytes>()
+            .map_or(0, |bytes| bytes.0);
+        let block = global::begin_block_with_bandwidth(span.name(), bytes);
+        OPEN_SPANS.with(|open| open.borrow_mut().push((id.clone(), block)));
+    }
+
+    fn on_exit(&self, id: &Id, _ctx: Context<'_, S>) {
+        // Exiting a span out of order also closes the spans entered after it,
+        // innermost first, so the block stack stays consistent.
+        OPEN_SPANS.with(|open| {
+            let mut open = open.borrow_mut();
+            if let Some(position) = open.iter().rposition(|(open_id, _)| open_id == id) {
+                while open.len() > position {
+                    open.pop();
+                }
+            }
+        });
+    }
+}
+
+#[cfg(all(test, feature = "profiling"))]
+mod tests {
+    use super::*;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    #[test]
+    fn nested_spans_become_nested_anchors() {
+        let subscriber = tracing_subscriber::registry().with(ProfilerLayer::new());
+        tracing::subscriber::with_default(subscriber, || {
+            let outer = tracing::info_span!("span outer", bytes = 64u64);
+            let _outer = outer.enter();
+            for _ in 0..2 {
+                let inner = tracing::info_span!("span inner");
+                let _inner = inner.enter();
+            }
+        });
+
+        let report = global::report();
+        let anchor = |label: &str| {
+            report
+                .anchors
+                .iter()
+                .find(|anchor| anchor.label == label)
+                .unwrap_or_else(|| panic!("no anchor for {label}"))
+        };
+        assert_eq!(
+            (anchor("span outer").hits, anchor("span outer").bytes),
+            (1, 64)
+        );
+        assert_eq!(anchor("span inner").hits, 2);
+        assert_eq!(anchor("span inner").parent.as_deref(), Some("span outer"));
+    }
+}
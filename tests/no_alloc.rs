@@ -0,0 +1,40 @@
+//! Kept in its own test binary so the counting allocator only sees this test.
+#![cfg(all(feature = "std", feature = "profiling"))]
+
+use iperf_rs::{InstantTimeSource, Profiler};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+
+struct CountingAllocator;
+
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+#[test]
+fn repeated_blocks_do_not_allocate() {
+    let profiler = Profiler::with_time_source(InstantTimeSource);
+    drop(profiler.begin_block("hot loop"));
+
+    let before = ALLOCATIONS.with(Cell::get);
+    for _ in 0..10_000 {
+        drop(profiler.begin_block("hot loop"));
+    }
+    assert_eq!(ALLOCATIONS.with(Cell::get), before);
+
+    assert_eq!(profiler.anchor("hot loop").unwrap().hits, 10_001);
+}